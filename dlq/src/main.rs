@@ -0,0 +1,213 @@
+use blarg::{
+    CommandLineParser, Condition, Optional, Parameter, Scalar, SubCommand, Switch, derive::*,
+    prelude::*,
+};
+use majdool_lib::api::Retry;
+use majdool_lib::db::database::tmp_initialize;
+use majdool_lib::fs::filesystem::MediaFilesystem;
+use majdool_lib::library::check_library;
+use majdool_lib::media::MediaSystem;
+use majdool_lib::monitor::ConsistencyMonitor;
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Hash, BlargChoices)]
+enum Command {
+    #[blarg(help = "List the dead letters")]
+    List,
+    #[blarg(help = "Retry the flushes and rechecks of the dead letters")]
+    Retry,
+    #[blarg(help = "Discard a dead letter")]
+    Discard,
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::List => write!(f, "list"),
+            Command::Retry => write!(f, "retry"),
+            Command::Discard => write!(f, "discard"),
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "list" => Ok(Command::List),
+            "retry" => Ok(Command::Retry),
+            "discard" => Ok(Command::Discard),
+            _ => Err(format!("unknown: {}", value)),
+        }
+    }
+}
+
+#[derive(BlargParser)]
+#[blarg(program = "majdool_dlq", initializer = initial)]
+struct Args {
+    #[blarg(
+        command = (Command::List, ListArgs),
+        command = (Command::Retry, RetryArgs),
+        command = (Command::Discard, DiscardArgs),
+        choices,
+    )]
+    command: Command,
+}
+
+impl Args {
+    fn initial() -> Self {
+        Self {
+            // This is an argument, so it is always overwritten.
+            command: Command::List,
+        }
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "List the dead letters, oldest first.")]
+struct ListArgs {}
+
+impl ListArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Retry the dead letters (removing those which succeed): the paths of flushes and imports are flushed again, and those of rechecks are rechecked. Other dead letters (ex: placements, cleanups and watch errors) can't be retried - discard them once handled."
+)]
+struct RetryArgs {
+    #[blarg(help = "Target directory path to flush to")]
+    target: String,
+    #[blarg(option, help = "Only retry this dead letter")]
+    id: Option<i64>,
+    #[blarg(help = "Write a sidecar next to each flushed media (as with the `sidecars` config)")]
+    sidecars: bool,
+    #[blarg(help = "Cache the hash of each flushed media (as with the `hash_cache` config)")]
+    hash_cache: bool,
+}
+
+impl RetryArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Discard a dead letter.")]
+struct DiscardArgs {
+    #[blarg(help = "The dead letter id")]
+    id: i64,
+}
+
+impl DiscardArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let (args, _, retry, discard): (Args, ListArgs, RetryArgs, DiscardArgs) = Args::blarg_parse();
+    let mut index_db = tmp_initialize().await;
+
+    match args.command {
+        Command::List => {
+            for dead_letter in index_db.dead_letters(None).await.unwrap() {
+                println!(
+                    "{} [{}] ({}) {} {:?}: {} (attempts: {})",
+                    dead_letter.id,
+                    dead_letter.created_at,
+                    dead_letter.retry.map_or("no retry", Retry::as_str),
+                    dead_letter.event,
+                    dead_letter.paths,
+                    dead_letter.reason,
+                    dead_letter.attempts
+                );
+            }
+        }
+        Command::Retry => {
+            let target = Path::new(&retry.target);
+
+            if !target.exists() || !target.is_dir() {
+                panic!("invalid target path (must exist and be a directory): {target:?}")
+            }
+
+            let target = target.canonicalize().unwrap();
+            let mut system_db = tmp_initialize().await;
+
+            if let Err(e) = check_library(&mut system_db, &target).await {
+                println!("{e}");
+                exit(1);
+            }
+
+            let mut filesystem = MediaFilesystem::new(&target);
+
+            if retry.sidecars {
+                filesystem = filesystem.with_sidecars();
+            }
+
+            if retry.hash_cache {
+                filesystem = filesystem.with_hash_cache();
+            }
+
+            let mut system = MediaSystem::new(system_db, filesystem);
+            let mut monitor = ConsistencyMonitor::new(tmp_initialize().await);
+
+            for dead_letter in index_db.dead_letters(retry.id).await.unwrap() {
+                let mut failures = Vec::default();
+
+                match dead_letter.retry {
+                    Some(Retry::Flush) => {
+                        for path in &dead_letter.paths {
+                            // A target file is never flushed back into the target.
+                            if !path.is_file()
+                                || path.starts_with(&target)
+                                || system.flush_file(path).await.is_err()
+                            {
+                                failures.push(path);
+                            }
+                        }
+                    }
+                    Some(Retry::Recheck) => {
+                        for path in &dead_letter.paths {
+                            if !path.starts_with(&target) || monitor.recheck(path).await.is_err() {
+                                failures.push(path);
+                            }
+                        }
+                    }
+                    None => {
+                        println!(
+                            "{}: {} can't be retried (discard it once handled)",
+                            dead_letter.id, dead_letter.event
+                        );
+                        continue;
+                    }
+                }
+
+                if failures.is_empty() {
+                    index_db.dead_letter_delete(dead_letter.id).await.unwrap();
+                    println!("{}: retried", dead_letter.id);
+                } else {
+                    let reason = format!("retry failed: {failures:?}");
+                    index_db
+                        .dead_letter_attempted(dead_letter.id, &reason)
+                        .await
+                        .unwrap();
+                    println!("{}: {reason}", dead_letter.id);
+                }
+            }
+        }
+        Command::Discard => {
+            if index_db.dead_letter_delete(discard.id).await.unwrap() {
+                println!("{}: discarded", discard.id);
+            } else {
+                println!("{}: not found", discard.id);
+            }
+        }
+    }
+}
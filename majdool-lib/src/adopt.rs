@@ -0,0 +1,124 @@
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::filesystem::MediaFilesystem;
+use crate::fs::fsutil::{compute_file_hash, content_wise_equals, walk_files};
+use crate::fs::receipt::is_receipt;
+use crate::fs::sidecar::is_sidecar;
+use crate::fs::sniff::sniff_file;
+use crate::library::is_marker;
+use crate::rebuild::parse_media_id;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Registers the files of an existing library (already beneath the target) in place, rather than flushing copies of them.
+/// Each file is indexed just as a flush would index it, except that it's renamed to its `$ID` within its current directory.
+pub struct LibraryAdopter {
+    index_db: MediaIndexDatabase,
+    filesystem: MediaFilesystem,
+}
+
+#[derive(Debug, Default)]
+pub struct AdoptReport {
+    pub adopted: usize,
+    /// Files whose adoption was interrupted after the rename, and has now been completed.
+    pub resumed: usize,
+    /// Files which were adopted by an earlier run (or flushed).
+    pub existing: usize,
+    /// Files whose content already exists on the target - they're left as is, just as a flush would skip them.
+    pub duplicates: Vec<PathBuf>,
+    /// Files named as media, which the index doesn't agree with (see the Consistency Monitor).
+    pub conflicts: Vec<PathBuf>,
+    pub failed: Vec<PathBuf>,
+}
+
+enum Adoption {
+    Adopted,
+    Resumed,
+    Existing,
+    Duplicate,
+    Conflict,
+}
+
+impl LibraryAdopter {
+    pub fn new(index_db: MediaIndexDatabase, filesystem: MediaFilesystem) -> Self {
+        Self {
+            index_db,
+            filesystem,
+        }
+    }
+
+    /// Adopt every file beneath the `directory` (which must be beneath the target).
+    /// This is resumable: files which are already named as media are only checked against the index (and completed, if they were interrupted).
+    pub async fn adopt(&mut self, directory: impl AsRef<Path>) -> Result<AdoptReport, ()> {
+        let directory = directory.as_ref();
+
+        if !directory.starts_with(self.filesystem.root()) {
+            return Err(());
+        }
+
+        let mut report = AdoptReport::default();
+
+        for path in walk_files(directory).await.map_err(|_| ())? {
+            if is_sidecar(&path) || is_receipt(&path) || is_marker(&path) {
+                continue;
+            }
+
+            match self.adopt_file(&path).await {
+                Ok(Adoption::Adopted) => report.adopted += 1,
+                Ok(Adoption::Resumed) => report.resumed += 1,
+                Ok(Adoption::Existing) => report.existing += 1,
+                Ok(Adoption::Duplicate) => report.duplicates.push(path),
+                Ok(Adoption::Conflict) => report.conflicts.push(path),
+                Err(_) => report.failed.push(path),
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn adopt_file(&mut self, path: &Path) -> Result<Adoption, ()> {
+        if let Some(id) = parse_media_id(path) {
+            return match self.index_db.media_state(id).await? {
+                Some(state) if state.synced && state.path.as_deref() == Some(path) => {
+                    Ok(Adoption::Existing)
+                }
+                // Renamed, but not yet synced (the rename happens after the insert).
+                Some(state) if !state.synced && !state.lost => {
+                    let hash = compute_file_hash(path).await.map_err(|_| ())?;
+
+                    if hash == state.hash {
+                        self.index_db.media_sync(id, path).await?;
+                        Ok(Adoption::Resumed)
+                    } else {
+                        Ok(Adoption::Conflict)
+                    }
+                }
+                _ => Ok(Adoption::Conflict),
+            };
+        }
+
+        let hash = compute_file_hash(path).await.map_err(|_| ())?;
+
+        if let Some(media) = self.index_db.media_lookup(hash).await {
+            match content_wise_equals(path, &media.path).await {
+                Ok(true) => return Ok(Adoption::Duplicate),
+                Ok(false) => {}
+                // The match is gone from the target, so this is the only copy.
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(_) => return Err(()),
+            }
+        }
+
+        // The same order as a flush: an interrupted adoption leaves an un-synced row, which is either resumed or garbage collected.
+        let file_type = sniff_file(path).await.map_err(|_| ())?;
+        let id = self
+            .index_db
+            .media_insert(&hash, path, file_type.as_ref())
+            .await?;
+        let destination = self
+            .filesystem
+            .adopt_write(path, id, &hash, file_type.as_ref())
+            .await?;
+        self.index_db.media_sync(id, destination).await?;
+        Ok(Adoption::Adopted)
+    }
+}
@@ -1,7 +1,7 @@
 use crate::fs::fsutil::FileHash;
 use std::path::PathBuf;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct MediaId {
     pub value: i64,
 }
@@ -16,6 +16,19 @@ impl MediaId {
     pub fn file_base(self) -> String {
         hex::encode(self.value.to_be_bytes())
     }
+
+    /// Parse the id back out of a `file_base` (ex: the file stem of a flushed file).
+    pub fn from_file_base(file_base: &str) -> Option<Self> {
+        let mut bytes = [0u8; 8];
+        hex::decode_to_slice(file_base, &mut bytes).ok()?;
+        let value = i64::from_be_bytes(bytes);
+
+        if value < 0 {
+            None
+        } else {
+            Some(Self::new(value))
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -25,6 +38,140 @@ pub struct Media {
     pub hash: FileHash,
 }
 
+/// The outcome of flushing a source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flush {
+    /// The file was copied onto the target as new media.
+    Flushed(MediaId),
+    /// The file already exists on the target (a content-wise match).
+    Duplicate(MediaId),
+}
+
+impl Flush {
+    pub fn id(self) -> MediaId {
+        match self {
+            Flush::Flushed(id) | Flush::Duplicate(id) => id,
+        }
+    }
+}
+
+/// What became of a source file, once it was flushed (see the source's `cleanup`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cleaned {
+    Kept,
+    Deleted,
+    /// Moved to this path, in the source's `.imported/` directory.
+    Moved(PathBuf),
+    /// The cleanup was verified, but not carried out.
+    DryRun,
+}
+
+impl Cleaned {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cleaned::Kept => "kept",
+            Cleaned::Deleted => "deleted",
+            Cleaned::Moved(_) => "moved",
+            Cleaned::DryRun => "dry-run",
+        }
+    }
+}
+
+/// A `media_index` row in any state (including un-synced and lost).
+#[derive(Debug)]
+pub struct MediaState {
+    pub id: MediaId,
+    pub path: Option<PathBuf>,
+    pub hash: FileHash,
+    pub synced: bool,
+    pub lost: bool,
+}
+
+/// Everything the index records about a media, beyond its labels.
+#[derive(Debug)]
+pub struct MediaDetails {
+    pub state: MediaState,
+    pub original_name: Option<String>,
+    pub source: Option<String>,
+    pub last_verified_at: Option<String>,
+    pub verification: Option<String>,
+    /// The media kind and MIME type sniffed from its content (unknown for unrecognized files, and those indexed before sniffing).
+    pub kind: Option<String>,
+    pub mime: Option<String>,
+}
+
+/// The number of `media_index` rows in each state.
+#[derive(Debug, Default)]
+pub struct MediaCounts {
+    pub synced: i64,
+    pub unsynced: i64,
+    pub lost: i64,
+}
+
+/// An event (or failed flush) which couldn't be handled, recorded so that it can be retried or discarded.
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub id: i64,
+    /// How `dlq retry` redoes it - the rest can only be discarded, once handled by hand.
+    pub retry: Option<Retry>,
+    /// The watcher event (ex: `Modify(Name(To))`), or the operation which failed (ex: `flush`).
+    pub event: String,
+    pub paths: Vec<PathBuf>,
+    pub reason: String,
+    pub attempts: i32,
+    pub created_at: String,
+}
+
+/// How a dead letter is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    /// Flush the (source) paths again.
+    Flush,
+    /// Recheck the (target) paths.
+    Recheck,
+}
+
+impl Retry {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Retry::Flush => "flush",
+            Retry::Recheck => "recheck",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "flush" => Some(Retry::Flush),
+            "recheck" => Some(Retry::Recheck),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of reading a synced file back and comparing it against its indexed hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// The on-disk content matches `media_index.hash`.
+    Verified,
+    /// The on-disk content does not match `media_index.hash`.
+    Corrupt,
+    /// There is no file at `media_index.path`.
+    Missing,
+    /// The file exists, but could not be read.
+    Unreadable,
+}
+
+impl Verification {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Verification::Verified => "verified",
+            Verification::Corrupt => "corrupt",
+            Verification::Missing => "missing",
+            Verification::Unreadable => "unreadable",
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -37,4 +184,22 @@ mod tests {
         let id = MediaId { value: i64::MAX };
         assert_eq!(id.file_base(), "7fffffffffffffff");
     }
+
+    #[test]
+    fn test_media_id_from_file_base() {
+        let id = MediaId::from_file_base("0000000000000001").unwrap();
+        assert_eq!(id.value, 1);
+
+        let id = MediaId::from_file_base("7fffffffffffffff").unwrap();
+        assert_eq!(id.value, i64::MAX);
+
+        let id = MediaId::from_file_base(&MediaId::new(42).file_base()).unwrap();
+        assert_eq!(id.value, 42);
+
+        assert!(MediaId::from_file_base("").is_none());
+        assert!(MediaId::from_file_base("01").is_none());
+        assert!(MediaId::from_file_base("000000000000000g").is_none());
+        assert!(MediaId::from_file_base("00000000000000001").is_none());
+        assert!(MediaId::from_file_base("8000000000000000").is_none());
+    }
 }
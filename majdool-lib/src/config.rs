@@ -0,0 +1,315 @@
+use crate::db::database::DEFAULT_DATABASE_URL;
+use crate::filter::Filter;
+use crate::fs::filesystem::{Layout, MediaFilesystem};
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+
+/// The directory (within a source) which `Cleanup::Move` moves the flushed source files into.
+pub const IMPORTED: &str = ".imported";
+
+/// The majdool configuration (a TOML file), declaring the database, the target and each of the sources synced into it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "default_database")]
+    pub database: String,
+    pub target: PathBuf,
+    /// How the target's flush directory is laid out (see `majdool reshard`, to convert a flat one).
+    #[serde(default)]
+    pub layout: Layout,
+    /// Write a JSON sidecar (`$ID.majdool.json`) next to each media, so that a rebuild recovers its provenance and labels.
+    #[serde(default)]
+    pub sidecars: bool,
+    /// Cache the hash of each flushed media in its extended attributes (see `HashCache`), so that rechecks needn't re-hash it.
+    #[serde(default)]
+    pub hash_cache: bool,
+    /// The number of concurrent flush workers, shared by all the sources.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    /// A mount root to watch for removable drives (ex: `/media`), each of which is flushed when mounted.
+    #[serde(default)]
+    pub mounts: Option<PathBuf>,
+    /// Write a receipt (`.majdool-receipt.json`) to each removable drive once it is fully flushed.
+    #[serde(default)]
+    pub receipts: bool,
+    /// Which files are flushed, from the sources and drives alike.
+    #[serde(default)]
+    pub filter: Filter,
+    /// Seconds to let in-flight flushes finish when shutting down.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default, rename = "source")]
+    pub sources: Vec<SourceConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub watcher: Watcher,
+    /// Seconds between rescans of the source, with the poll watcher.
+    #[serde(default = "default_poll")]
+    pub poll: u64,
+    /// Seconds a file's size and mtime must stay unchanged before it is flushed.
+    #[serde(default = "default_quiet")]
+    pub quiet: u64,
+    /// The number of watcher events which can be queued, while earlier ones are being handled.
+    #[serde(default = "default_queue")]
+    pub queue: usize,
+    /// What happens to the watcher events which don't fit in the queue.
+    #[serde(default)]
+    pub overflow: Overflow,
+    /// Seconds between full rescans of the source, as a safety net for missed events (0 to disable).
+    #[serde(default)]
+    pub rescan: u64,
+    /// Glob patterns of the files to ignore (on top of the `filter`'s excludes), matched against both the file name and the path within the source.
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// The directory (relative to the target) which newly flushed media is moved into.
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    /// A label applied to each media flushed from the source.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// What happens to each source file, once it is flushed (and its target copy verified).
+    #[serde(default)]
+    pub cleanup: Cleanup,
+    /// Only log the cleanups, without carrying them out.
+    #[serde(default)]
+    pub cleanup_dry_run: bool,
+    #[serde(skip)]
+    filter: Filter,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Watcher {
+    /// Filesystem events (inotify, etc).
+    #[default]
+    Notify,
+    /// Periodic rescans, for filesystems without events (NFS/SMB, some FUSE or exFAT).
+    Poll,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// Spill the paths of the events to disk, and replay them once the queue drains.
+    #[default]
+    Spill,
+    /// Rescan the whole source once the queue drains.
+    Rescan,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cleanup {
+    /// Leave the source file alone.
+    #[default]
+    Keep,
+    /// Delete the source file.
+    Delete,
+    /// Move the source file into the source's `.imported/` directory (keeping its relative path).
+    Move,
+}
+
+/// Where `Cleanup::Move` moves the file at `path`, beneath the `source`.
+pub fn imported_path(source: impl AsRef<Path>, path: impl AsRef<Path>) -> Option<PathBuf> {
+    let relative = path.as_ref().strip_prefix(&source).ok()?;
+    Some(source.as_ref().join(IMPORTED).join(relative))
+}
+
+fn default_database() -> String {
+    DEFAULT_DATABASE_URL.to_string()
+}
+
+fn default_workers() -> usize {
+    4
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_poll() -> u64 {
+    30
+}
+
+fn default_queue() -> usize {
+    1024
+}
+
+fn default_quiet() -> u64 {
+    5
+}
+
+impl Config {
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let content = tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| format!("failed to read {:?}: {e}", path.as_ref()))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut config: Config = toml::from_str(content).map_err(|e| e.to_string())?;
+        config.filter = config.filter.compile()?;
+
+        for source in &mut config.sources {
+            if let Some(directory) = &source.directory
+                && !directory
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(format!(
+                    "source directory must be relative to the target: {directory:?}"
+                ));
+            }
+
+            source.filter = config.filter.with_excludes(&source.ignore)?;
+        }
+
+        Ok(config)
+    }
+
+    /// The target's filesystem, laid out as configured (`root` is the resolved `target`).
+    pub fn filesystem(&self, root: impl Into<PathBuf>) -> MediaFilesystem {
+        let mut filesystem = MediaFilesystem::new(root).with_layout(self.layout);
+
+        if self.sidecars {
+            filesystem = filesystem.with_sidecars();
+        }
+
+        if self.hash_cache {
+            filesystem = filesystem.with_hash_cache();
+        }
+
+        filesystem
+    }
+}
+
+impl SourceConfig {
+    /// Whether the `path` (beneath this source) is excluded by the filter or the ignore patterns.
+    /// The source's `.imported/` directory is always ignored (see `imported`).
+    pub fn ignores(&self, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        self.imported(path) || self.filter.excludes(&self.path, path)
+    }
+
+    /// Whether the `path` is in the source's `.imported/` directory, whose files have already been flushed.
+    pub fn imported(&self, path: impl AsRef<Path>) -> bool {
+        path.as_ref()
+            .strip_prefix(&self.path)
+            .is_ok_and(|relative| relative.starts_with(IMPORTED))
+    }
+
+    /// Whether the file at `path` (beneath this source), of `size` bytes, should be flushed.
+    pub fn accepts(&self, path: impl AsRef<Path>, size: u64) -> bool {
+        self.filter.accepts_size(size) && !self.ignores(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sources() {
+        let config = Config::parse(
+            r#"
+target = "/srv/library"
+layout = "sharded"
+sidecars = true
+hash_cache = true
+workers = 2
+
+[filter]
+exclude = ["Thumbs.db"]
+max_size = 1000000
+
+[[source]]
+path = "/srv/inbox/phone"
+ignore = [".DS_Store", "*.tmp", "cache/**"]
+directory = "phones/lindsey"
+label = "phone"
+cleanup = "move"
+
+[[source]]
+path = "/mnt/nas/camera"
+watcher = "poll"
+poll = 60
+queue = 16
+overflow = "rescan"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.database, DEFAULT_DATABASE_URL);
+        assert_eq!(config.target, PathBuf::from("/srv/library"));
+        assert_eq!(config.layout, Layout::Sharded);
+        assert!(config.sidecars);
+        assert!(config.hash_cache);
+        assert_eq!(config.workers, 2);
+        assert_eq!(config.mounts, None);
+        assert!(!config.receipts);
+        assert_eq!(config.shutdown_timeout, 30);
+        assert_eq!(config.sources.len(), 2);
+
+        let phone = &config.sources[0];
+        assert_eq!(phone.watcher, Watcher::Notify);
+        assert_eq!(phone.quiet, 5);
+        assert_eq!(phone.queue, 1024);
+        assert_eq!(phone.overflow, Overflow::Spill);
+        assert_eq!(phone.directory, Some(PathBuf::from("phones/lindsey")));
+        assert_eq!(phone.label.as_deref(), Some("phone"));
+        assert!(phone.ignores("/srv/inbox/phone/DCIM/.DS_Store"));
+        assert!(phone.ignores("/srv/inbox/phone/upload.tmp"));
+        assert!(phone.ignores("/srv/inbox/phone/cache/thumb.jpg"));
+        assert!(!phone.ignores("/srv/inbox/phone/DCIM/IMG_0001.jpg"));
+        // The filter's excludes replace the defaults.
+        assert!(!phone.ignores("/srv/inbox/phone/DCIM/._IMG_0001.jpg"));
+        assert!(phone.ignores("/srv/inbox/phone/DCIM/thumbs.db"));
+        assert!(phone.accepts("/srv/inbox/phone/DCIM/IMG_0001.jpg", 1));
+        assert!(!phone.accepts("/srv/inbox/phone/DCIM/IMG_0001.jpg", 0));
+        assert!(!phone.accepts("/srv/inbox/phone/DCIM/IMG_0001.jpg", 1000001));
+        assert!(phone.ignores("/srv/inbox/phone/.imported/DCIM/IMG_0001.jpg"));
+        assert_eq!(phone.cleanup, Cleanup::Move);
+        assert_eq!(
+            imported_path(&phone.path, "/srv/inbox/phone/DCIM/IMG_0001.jpg"),
+            Some(PathBuf::from(
+                "/srv/inbox/phone/.imported/DCIM/IMG_0001.jpg"
+            ))
+        );
+        assert_eq!(
+            imported_path(&phone.path, "/srv/elsewhere/IMG_0001.jpg"),
+            None
+        );
+
+        let camera = &config.sources[1];
+        assert_eq!(camera.watcher, Watcher::Poll);
+        assert_eq!(camera.poll, 60);
+        assert_eq!(camera.queue, 16);
+        assert_eq!(camera.overflow, Overflow::Rescan);
+        assert!(!camera.ignores("/mnt/nas/camera/.DS_Store"));
+        assert!(camera.ignores("/mnt/nas/camera/Thumbs.db"));
+        assert_eq!(camera.cleanup, Cleanup::Keep);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Config::parse("workers = 2").is_err());
+        assert!(Config::parse("target = \"/t\"\nunknown = 1").is_err());
+        assert!(
+            Config::parse("target = \"/t\"\n[[source]]\npath = \"/s\"\nwatcher = \"fanotify\"")
+                .is_err()
+        );
+        assert!(
+            Config::parse("target = \"/t\"\n[[source]]\npath = \"/s\"\nignore = [\"[\"]").is_err()
+        );
+        assert!(
+            Config::parse("target = \"/t\"\n[[source]]\npath = \"/s\"\ndirectory = \"../escape\"")
+                .is_err()
+        );
+    }
+}
@@ -1,14 +1,24 @@
-use crate::api::{Media, MediaId};
-use crate::db::model::{MediaIndex, MediaIndexView};
+use crate::api::{
+    Cleaned, DeadLetter, Media, MediaCounts, MediaDetails, MediaId, MediaState, Retry, Verification,
+};
+use crate::db::model::{
+    DeadLetterQueue, DeadLetterView, DeviceImport, Library, MediaDetailsView, MediaIndex,
+    MediaIndexStateView, MediaIndexView, MediaLabel, SourceCleanup,
+};
+use crate::device::Volume;
 use crate::fs::fsutil::FileHash;
-use sea_query::{Expr, ExprTrait, PostgresQueryBuilder, Query};
+use crate::fs::sniff::FileType;
+use sea_query::{
+    Cond, Expr, ExprTrait, NullOrdering, OnConflict, Order, PostgresQueryBuilder, Query,
+};
 use sea_query_sqlx::SqlxBinder;
 use sqlx::pool::PoolConnection;
 use sqlx::{PgPool, Postgres};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub struct MediaIndexDatabase {
-    pool: PoolConnection<Postgres>,
+    pub(super) pool: PoolConnection<Postgres>,
 }
 
 impl MediaIndexDatabase {
@@ -33,11 +43,33 @@ impl MediaIndexDatabase {
         }
     }
 
-    pub async fn media_insert(&mut self, hash: &FileHash) -> Result<MediaId, ()> {
+    pub async fn media_insert(
+        &mut self,
+        hash: &FileHash,
+        source: impl AsRef<Path>,
+        file_type: Option<&FileType>,
+    ) -> Result<MediaId, ()> {
+        let original_name = source.as_ref().file_name().and_then(|n| n.to_str());
         let (sql, values) = Query::insert()
             .into_table(MediaIndex::Table)
-            .columns([MediaIndex::Hash, MediaIndex::Synced, MediaIndex::Lost])
-            .values_panic([hash.as_ref().into(), false.into(), false.into()])
+            .columns([
+                MediaIndex::Hash,
+                MediaIndex::Synced,
+                MediaIndex::Lost,
+                MediaIndex::OriginalName,
+                MediaIndex::Source,
+                MediaIndex::Kind,
+                MediaIndex::Mime,
+            ])
+            .values_panic([
+                hash.as_ref().into(),
+                false.into(),
+                false.into(),
+                original_name.into(),
+                source.as_ref().to_str().into(),
+                file_type.map(|t| t.kind.as_str()).into(),
+                file_type.map(|t| t.mime).into(),
+            ])
             .returning_col(MediaIndex::Id)
             .build_sqlx(PostgresQueryBuilder);
 
@@ -64,14 +96,692 @@ impl MediaIndexDatabase {
             .map(|_| ())
             .map_err(|_| ())
     }
+
+    pub async fn media_get(&mut self, id: MediaId) -> Option<Media> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .column(MediaIndex::Id)
+            .column(MediaIndex::Path)
+            .column(MediaIndex::Hash)
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .and_where(Expr::col(MediaIndex::Synced).eq(true))
+            .and_where(Expr::col(MediaIndex::Lost).eq(false))
+            .build_sqlx(PostgresQueryBuilder);
+
+        let row = sqlx::query_as_with::<_, MediaIndexView, _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await;
+
+        match row {
+            Ok(miv) => Some(Media::from(miv)),
+            Err(_) => None,
+        }
+    }
+
+    /// List the synced media (optionally, only those with the `label`), in id order.
+    pub async fn media_list(&mut self, label: Option<&str>, limit: u64) -> Result<Vec<Media>, ()> {
+        let mut query = Query::select();
+        query
+            .from(MediaIndex::Table)
+            .column((MediaIndex::Table, MediaIndex::Id))
+            .column((MediaIndex::Table, MediaIndex::Path))
+            .column((MediaIndex::Table, MediaIndex::Hash))
+            .and_where(Expr::col((MediaIndex::Table, MediaIndex::Synced)).eq(true))
+            .and_where(Expr::col((MediaIndex::Table, MediaIndex::Lost)).eq(false))
+            .order_by((MediaIndex::Table, MediaIndex::Id), Order::Asc)
+            .limit(limit);
+
+        if let Some(label) = label {
+            query
+                .inner_join(
+                    MediaLabel::Table,
+                    Expr::col((MediaLabel::Table, MediaLabel::MediaIndexId))
+                        .equals((MediaIndex::Table, MediaIndex::Id)),
+                )
+                .and_where(Expr::col((MediaLabel::Table, MediaLabel::Label)).eq(label));
+        }
+
+        let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, MediaIndexView, _>(&sql, values)
+            .fetch_all(&mut *self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(Media::from).collect())
+            .map_err(|_| ())
+    }
+
+    /// Get everything the index records for `id`, regardless of its state.
+    pub async fn media_details(&mut self, id: MediaId) -> Result<Option<MediaDetails>, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .column(MediaIndex::Id)
+            .column(MediaIndex::Path)
+            .column(MediaIndex::Hash)
+            .column(MediaIndex::Synced)
+            .column(MediaIndex::Lost)
+            .column(MediaIndex::OriginalName)
+            .column(MediaIndex::Source)
+            .expr_as(
+                Expr::cust("last_verified_at::TEXT"),
+                MediaIndex::LastVerifiedAt,
+            )
+            .column(MediaIndex::Verification)
+            .column(MediaIndex::Kind)
+            .column(MediaIndex::Mime)
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, MediaDetailsView, _>(&sql, values)
+            .fetch_optional(&mut *self.pool)
+            .await
+            .map(|row| row.map(MediaDetails::from))
+            .map_err(|_| ())
+    }
+
+    /// The rows which were never synced (ex: a flush which failed or was interrupted).
+    pub async fn media_unsynced(&mut self) -> Result<Vec<MediaState>, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .column(MediaIndex::Id)
+            .column(MediaIndex::Path)
+            .column(MediaIndex::Hash)
+            .column(MediaIndex::Synced)
+            .column(MediaIndex::Lost)
+            .and_where(Expr::col(MediaIndex::Synced).eq(false))
+            .order_by(MediaIndex::Id, Order::Asc)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, MediaIndexStateView, _>(&sql, values)
+            .fetch_all(&mut *self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(MediaState::from).collect())
+            .map_err(|_| ())
+    }
+
+    /// Delete the row (and its labels), returning whether it existed.
+    pub async fn media_delete(&mut self, id: MediaId) -> Result<bool, ()> {
+        let (sql, values) = Query::delete()
+            .from_table(MediaLabel::Table)
+            .and_where(Expr::col(MediaLabel::MediaIndexId).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map_err(|_| ())?;
+
+        let (sql, values) = Query::delete()
+            .from_table(MediaIndex::Table)
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|result| result.rows_affected() > 0)
+            .map_err(|_| ())
+    }
+
+    pub async fn media_counts(&mut self) -> Result<MediaCounts, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .expr(Expr::cust("COUNT(*) FILTER (WHERE synced AND NOT lost)"))
+            .expr(Expr::cust("COUNT(*) FILTER (WHERE NOT synced)"))
+            .expr(Expr::cust("COUNT(*) FILTER (WHERE lost)"))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64, i64, i64), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|(synced, unsynced, lost)| MediaCounts {
+                synced,
+                unsynced,
+                lost,
+            })
+            .map_err(|_| ())
+    }
+
+    /// Get the row for `id`, regardless of its state.
+    pub async fn media_state(&mut self, id: MediaId) -> Result<Option<MediaState>, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .column(MediaIndex::Id)
+            .column(MediaIndex::Path)
+            .column(MediaIndex::Hash)
+            .column(MediaIndex::Synced)
+            .column(MediaIndex::Lost)
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, MediaIndexStateView, _>(&sql, values)
+            .fetch_optional(&mut *self.pool)
+            .await
+            .map(|row| row.map(MediaState::from))
+            .map_err(|_| ())
+    }
+
+    /// Point the row for `id` at the on-disk file (see fixes A & B), recovering it if it was lost.
+    pub async fn media_relocate(
+        &mut self,
+        id: MediaId,
+        path: impl AsRef<Path>,
+        hash: &FileHash,
+    ) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(MediaIndex::Table)
+            .values([
+                (MediaIndex::Path, path.as_ref().to_str().into()),
+                (MediaIndex::Hash, hash.as_ref().into()),
+                (MediaIndex::Synced, true.into()),
+                (MediaIndex::Lost, false.into()),
+            ])
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Mark the synced rows at `path` (or anywhere beneath it) as lost, except for `except` (see fix D).
+    /// Returns the number of rows lost.
+    pub async fn media_lose(
+        &mut self,
+        path: impl AsRef<Path>,
+        except: Option<MediaId>,
+    ) -> Result<u64, ()> {
+        let path = path.as_ref().to_str().ok_or(())?;
+        let mut query = Query::update();
+        query
+            .table(MediaIndex::Table)
+            .values([(MediaIndex::Lost, true.into())])
+            .and_where(Expr::col(MediaIndex::Synced).eq(true))
+            .and_where(Expr::col(MediaIndex::Lost).eq(false))
+            .cond_where(Cond::any().add(Expr::col(MediaIndex::Path).eq(path)).add(
+                Expr::cust_with_values(
+                    "starts_with(path, $1)",
+                    [format!("{}/", path.trim_end_matches('/'))],
+                ),
+            ));
+
+        if let Some(except) = except {
+            query.and_where(Expr::col(MediaIndex::Id).ne(except.value));
+        }
+
+        let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|r| r.rows_affected())
+            .map_err(|_| ())
+    }
+
+    /// Record where a media originally came from.
+    pub async fn media_provenance(
+        &mut self,
+        id: MediaId,
+        original_name: Option<&str>,
+        source: Option<&str>,
+    ) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(MediaIndex::Table)
+            .values([
+                (MediaIndex::OriginalName, original_name.into()),
+                (MediaIndex::Source, source.into()),
+            ])
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Record the type sniffed from a media's content.
+    pub async fn media_file_type(&mut self, id: MediaId, file_type: &FileType) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(MediaIndex::Table)
+            .values([
+                (MediaIndex::Kind, file_type.kind.as_str().into()),
+                (MediaIndex::Mime, file_type.mime.into()),
+            ])
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    pub async fn media_labels(&mut self, id: MediaId) -> Result<Vec<String>, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaLabel::Table)
+            .column(MediaLabel::Label)
+            .and_where(Expr::col(MediaLabel::MediaIndexId).eq(id.value))
+            .order_by(MediaLabel::Label, Order::Asc)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (String,), _>(&sql, values)
+            .fetch_all(&mut *self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(|r| r.0).collect())
+            .map_err(|_| ())
+    }
+
+    pub async fn media_label(&mut self, id: MediaId, label: &str) -> Result<(), ()> {
+        let (sql, values) = Query::insert()
+            .into_table(MediaLabel::Table)
+            .columns([MediaLabel::MediaIndexId, MediaLabel::Label])
+            .values_panic([id.value.into(), label.into()])
+            .on_conflict(
+                OnConflict::columns([MediaLabel::MediaIndexId, MediaLabel::Label])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    pub async fn media_unlabel(&mut self, id: MediaId, label: &str) -> Result<(), ()> {
+        let (sql, values) = Query::delete()
+            .from_table(MediaLabel::Table)
+            .and_where(Expr::col(MediaLabel::MediaIndexId).eq(id.value))
+            .and_where(Expr::col(MediaLabel::Label).eq(label))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Insert an already synced media, retaining its original id.
+    pub async fn media_restore(&mut self, media: &Media) -> Result<(), ()> {
+        let (sql, values) = Query::insert()
+            .into_table(MediaIndex::Table)
+            .columns([
+                MediaIndex::Id,
+                MediaIndex::Path,
+                MediaIndex::Hash,
+                MediaIndex::Synced,
+                MediaIndex::Lost,
+            ])
+            .values_panic([
+                media.id.value.into(),
+                media.path.to_str().into(),
+                media.hash.as_ref().into(),
+                true.into(),
+                false.into(),
+            ])
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Move the id sequence past the maximum id, so that new inserts don't collide with restored media.
+    pub async fn media_reset_sequence(&mut self) -> Result<(), ()> {
+        sqlx::query(
+            "SELECT setval(pg_get_serial_sequence('media_index', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM media_index",
+        )
+        .execute(&mut *self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|_| ())
+    }
+
+    /// Count all the rows, regardless of their state.
+    pub async fn media_count(&mut self) -> Result<i64, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .expr(Expr::col(MediaIndex::Id).count())
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|c| c.0)
+            .map_err(|_| ())
+    }
+
+    /// Select the synced media which have gone the longest without being verified (never verified first).
+    pub async fn media_verification_candidates(&mut self, limit: u64) -> Result<Vec<Media>, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .column(MediaIndex::Id)
+            .column(MediaIndex::Path)
+            .column(MediaIndex::Hash)
+            .and_where(Expr::col(MediaIndex::Synced).eq(true))
+            .and_where(Expr::col(MediaIndex::Lost).eq(false))
+            .order_by_with_nulls(MediaIndex::LastVerifiedAt, Order::Asc, NullOrdering::First)
+            .order_by(MediaIndex::Id, Order::Asc)
+            .limit(limit)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, MediaIndexView, _>(&sql, values)
+            .fetch_all(&mut *self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(Media::from).collect())
+            .map_err(|_| ())
+    }
+
+    pub async fn media_verify(
+        &mut self,
+        id: MediaId,
+        verification: Verification,
+    ) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(MediaIndex::Table)
+            .values([
+                (MediaIndex::LastVerifiedAt, Expr::current_timestamp()),
+                (MediaIndex::Verification, Expr::val(verification.as_str())),
+            ])
+            .and_where(Expr::col(MediaIndex::Id).eq(id.value))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Count the synced media.
+    pub async fn media_synced_count(&mut self) -> Result<i64, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .expr(Expr::col(MediaIndex::Id).count())
+            .and_where(Expr::col(MediaIndex::Synced).eq(true))
+            .and_where(Expr::col(MediaIndex::Lost).eq(false))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|c| c.0)
+            .map_err(|_| ())
+    }
+
+    /// Count the synced media which have not been verified within the trailing `period`.
+    pub async fn media_verification_overdue_count(&mut self, period: Duration) -> Result<i64, ()> {
+        let (sql, values) = Query::select()
+            .from(MediaIndex::Table)
+            .expr(Expr::col(MediaIndex::Id).count())
+            .and_where(Expr::col(MediaIndex::Synced).eq(true))
+            .and_where(Expr::col(MediaIndex::Lost).eq(false))
+            .cond_where(
+                Cond::any()
+                    .add(Expr::col(MediaIndex::LastVerifiedAt).is_null())
+                    .add(
+                        Expr::col(MediaIndex::LastVerifiedAt).lt(Expr::cust_with_values(
+                            "NOW() - make_interval(secs => $1)",
+                            [period.as_secs_f64()],
+                        )),
+                    ),
+            )
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|c| c.0)
+            .map_err(|_| ())
+    }
+
+    pub async fn dead_letter_insert(
+        &mut self,
+        retry: Option<Retry>,
+        event: &str,
+        paths: &[PathBuf],
+        reason: &str,
+        attempts: i32,
+    ) -> Result<i64, ()> {
+        let paths = serde_json::to_string(paths).map_err(|_| ())?;
+        let (sql, values) = Query::insert()
+            .into_table(DeadLetterQueue::Table)
+            .columns([
+                DeadLetterQueue::Retry,
+                DeadLetterQueue::Event,
+                DeadLetterQueue::Paths,
+                DeadLetterQueue::Reason,
+                DeadLetterQueue::Attempts,
+            ])
+            .values_panic([
+                retry.map(Retry::as_str).into(),
+                event.into(),
+                paths.into(),
+                reason.into(),
+                attempts.into(),
+            ])
+            .returning_col(DeadLetterQueue::Id)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|i| i.0)
+            .map_err(|_| ())
+    }
+
+    /// Select the dead letters (or just the one for `id`), oldest first.
+    pub async fn dead_letters(&mut self, id: Option<i64>) -> Result<Vec<DeadLetter>, ()> {
+        let mut query = Query::select();
+        query
+            .from(DeadLetterQueue::Table)
+            .column(DeadLetterQueue::Id)
+            .column(DeadLetterQueue::Retry)
+            .column(DeadLetterQueue::Event)
+            .column(DeadLetterQueue::Paths)
+            .column(DeadLetterQueue::Reason)
+            .column(DeadLetterQueue::Attempts)
+            .expr_as(Expr::cust("created_at::TEXT"), DeadLetterQueue::CreatedAt)
+            .order_by(DeadLetterQueue::Id, Order::Asc);
+
+        if let Some(id) = id {
+            query.and_where(Expr::col(DeadLetterQueue::Id).eq(id));
+        }
+
+        let (sql, values) = query.build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, DeadLetterView, _>(&sql, values)
+            .fetch_all(&mut *self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(DeadLetter::from).collect())
+            .map_err(|_| ())
+    }
+
+    /// Record another failed attempt at handling the dead letter.
+    pub async fn dead_letter_attempted(&mut self, id: i64, reason: &str) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(DeadLetterQueue::Table)
+            .values([
+                (DeadLetterQueue::Reason, reason.into()),
+                (
+                    DeadLetterQueue::Attempts,
+                    Expr::col(DeadLetterQueue::Attempts).add(1),
+                ),
+            ])
+            .and_where(Expr::col(DeadLetterQueue::Id).eq(id))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    pub async fn dead_letter_delete(&mut self, id: i64) -> Result<bool, ()> {
+        let (sql, values) = Query::delete()
+            .from_table(DeadLetterQueue::Table)
+            .and_where(Expr::col(DeadLetterQueue::Id).eq(id))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|r| r.rows_affected() > 0)
+            .map_err(|_| ())
+    }
+
+    /// Record the start of a drive flush from the `volume`, returning the import id.
+    pub async fn device_import_start(&mut self, volume: &Volume) -> Result<i64, ()> {
+        let (sql, values) = Query::insert()
+            .into_table(DeviceImport::Table)
+            .columns([
+                DeviceImport::Device,
+                DeviceImport::Uuid,
+                DeviceImport::Label,
+                DeviceImport::MountPoint,
+            ])
+            .values_panic([
+                volume.device.to_string_lossy().to_string().into(),
+                volume.uuid.clone().into(),
+                volume.label.clone().into(),
+                volume.mount_point.to_string_lossy().to_string().into(),
+            ])
+            .returning_col(DeviceImport::Id)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|i| i.0)
+            .map_err(|_| ())
+    }
+
+    pub async fn device_import_finish(
+        &mut self,
+        id: i64,
+        files: usize,
+        bytes: u64,
+        failed: usize,
+        skipped: usize,
+    ) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(DeviceImport::Table)
+            .values([
+                (DeviceImport::Files, Expr::val(files as i64)),
+                (DeviceImport::Bytes, Expr::val(bytes as i64)),
+                (DeviceImport::Failed, Expr::val(failed as i64)),
+                (DeviceImport::Skipped, Expr::val(skipped as i64)),
+                (DeviceImport::FinishedAt, Expr::current_timestamp()),
+            ])
+            .and_where(Expr::col(DeviceImport::Id).eq(id))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Record (before acting on it) the cleanup of a flushed `source` file, returning the cleanup id.
+    pub async fn source_cleanup_start(
+        &mut self,
+        id: MediaId,
+        source: impl AsRef<Path>,
+        cleaned: &Cleaned,
+    ) -> Result<i64, ()> {
+        let destination = match cleaned {
+            Cleaned::Moved(destination) => Some(destination.to_string_lossy().to_string()),
+            _ => None,
+        };
+        let (sql, values) = Query::insert()
+            .into_table(SourceCleanup::Table)
+            .columns([
+                SourceCleanup::MediaIndexId,
+                SourceCleanup::Action,
+                SourceCleanup::Source,
+                SourceCleanup::Destination,
+            ])
+            .values_panic([
+                id.value.into(),
+                cleaned.as_str().into(),
+                source.as_ref().to_string_lossy().to_string().into(),
+                destination.into(),
+            ])
+            .returning_col(SourceCleanup::Id)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (i64,), _>(&sql, values)
+            .fetch_one(&mut *self.pool)
+            .await
+            .map(|i| i.0)
+            .map_err(|_| ())
+    }
+
+    pub async fn source_cleanup_finish(&mut self, id: i64) -> Result<(), ()> {
+        let (sql, values) = Query::update()
+            .table(SourceCleanup::Table)
+            .values([(SourceCleanup::FinishedAt, Expr::current_timestamp())])
+            .and_where(Expr::col(SourceCleanup::Id).eq(id))
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// The id of the library which this database indexes, if it has been initialized.
+    pub async fn library_id(&mut self) -> Result<Option<String>, ()> {
+        let (sql, values) = Query::select()
+            .from(Library::Table)
+            .column(Library::Id)
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_as_with::<_, (String,), _>(&sql, values)
+            .fetch_optional(&mut *self.pool)
+            .await
+            .map(|row| row.map(|r| r.0))
+            .map_err(|_| ())
+    }
+
+    /// Record the library which this database indexes (a database only ever indexes one).
+    pub async fn library_insert(&mut self, id: &str, target: impl AsRef<Path>) -> Result<(), ()> {
+        let (sql, values) = Query::insert()
+            .into_table(Library::Table)
+            .columns([Library::Id, Library::Target])
+            .values_panic([id.into(), target.as_ref().to_str().into()])
+            .build_sqlx(PostgresQueryBuilder);
+
+        sqlx::query_with(&sql, values)
+            .execute(&mut *self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+pub const DEFAULT_DATABASE_URL: &str = "postgres://lindsey@127.0.0.1/majdool";
+
+pub async fn connect(url: &str) -> Result<MediaIndexDatabase, ()> {
+    let connection = PgPool::connect(url).await.map_err(|_| ())?;
+    let pool = connection.try_acquire().ok_or(())?;
+    Ok(MediaIndexDatabase { pool })
 }
 
 pub async fn tmp_initialize() -> MediaIndexDatabase {
-    let connection = PgPool::connect("postgres://lindsey@127.0.0.1/majdool")
-        .await
-        .unwrap();
-    let pool = connection.try_acquire().unwrap();
-    MediaIndexDatabase { pool }
+    connect(DEFAULT_DATABASE_URL).await.unwrap()
 }
 
 // WIP
@@ -103,7 +813,7 @@ mod tests {
         sqlx::migrate!("./migrations").run(&pool).await.unwrap();
 
         // Now you can use the pool for your tests
-        let hash = [0u8; 32];
+        let _hash = [0u8; 32];
     }
     //
     // use testcontainers::clients;
@@ -133,7 +843,7 @@ mod tests {
     //         .unwrap();
     //
     //     // Your test code here
-    //     let hash = [0u8; 32];
+    //     let _hash = [0u8; 32];
     //     // ... rest of test
     // }
 }
@@ -0,0 +1,107 @@
+use crate::db::database::MediaIndexDatabase;
+use crate::db::model::SchemaMigration;
+use sea_query::{OnConflict, Order, PostgresQueryBuilder, Query};
+use sea_query_sqlx::SqlxBinder;
+use sqlx::Connection;
+
+/// The schema migrations (`migrations/$VERSION.up`), in order.
+const MIGRATIONS: [(i32, &str); 10] = [
+    (1, include_str!("../../migrations/1.up")),
+    (2, include_str!("../../migrations/2.up")),
+    (3, include_str!("../../migrations/3.up")),
+    (4, include_str!("../../migrations/4.up")),
+    (5, include_str!("../../migrations/5.up")),
+    (6, include_str!("../../migrations/6.up")),
+    (7, include_str!("../../migrations/7.up")),
+    (8, include_str!("../../migrations/8.up")),
+    (9, include_str!("../../migrations/9.up")),
+    (10, include_str!("../../migrations/10.up")),
+];
+
+const SCHEMA_MIGRATION: &str = "CREATE TABLE IF NOT EXISTS schema_migration (
+    version INTEGER PRIMARY KEY,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+)";
+
+impl MediaIndexDatabase {
+    /// Apply the pending migrations (each in its own transaction), returning their versions.
+    /// A database which was migrated by hand (before `schema_migration` existed) is first marked as being at the `baseline` version.
+    pub async fn migrate(&mut self, baseline: Option<i32>) -> Result<Vec<i32>, ()> {
+        sqlx::raw_sql(SCHEMA_MIGRATION)
+            .execute(&mut *self.pool)
+            .await
+            .map_err(|_| ())?;
+
+        if let Some(baseline) = baseline {
+            for (version, _) in MIGRATIONS.iter().filter(|(v, _)| *v <= baseline) {
+                let (sql, values) = Query::insert()
+                    .into_table(SchemaMigration::Table)
+                    .columns([SchemaMigration::Version])
+                    .values_panic([(*version).into()])
+                    .on_conflict(OnConflict::new().do_nothing().to_owned())
+                    .build_sqlx(PostgresQueryBuilder);
+
+                sqlx::query_with(&sql, values)
+                    .execute(&mut *self.pool)
+                    .await
+                    .map_err(|_| ())?;
+            }
+        }
+
+        let mut applied = Vec::default();
+
+        for version in self.migrations_pending().await? {
+            let (_, migration) = MIGRATIONS[version as usize - 1];
+            let (sql, values) = Query::insert()
+                .into_table(SchemaMigration::Table)
+                .columns([SchemaMigration::Version])
+                .values_panic([version.into()])
+                .build_sqlx(PostgresQueryBuilder);
+
+            let mut transaction = self.pool.begin().await.map_err(|_| ())?;
+            sqlx::raw_sql(migration)
+                .execute(&mut *transaction)
+                .await
+                .map_err(|_| ())?;
+            sqlx::query_with(&sql, values)
+                .execute(&mut *transaction)
+                .await
+                .map_err(|_| ())?;
+            transaction.commit().await.map_err(|_| ())?;
+            applied.push(version);
+        }
+
+        Ok(applied)
+    }
+
+    /// The versions of the migrations which haven't been applied yet.
+    pub async fn migrations_pending(&mut self) -> Result<Vec<i32>, ()> {
+        let (tracked,) =
+            sqlx::query_as::<_, (bool,)>("SELECT to_regclass('schema_migration') IS NOT NULL")
+                .fetch_one(&mut *self.pool)
+                .await
+                .map_err(|_| ())?;
+        let mut applied = Vec::default();
+
+        // Otherwise, the database has never been migrated (by us).
+        if tracked {
+            let (sql, values) = Query::select()
+                .from(SchemaMigration::Table)
+                .column(SchemaMigration::Version)
+                .order_by(SchemaMigration::Version, Order::Asc)
+                .build_sqlx(PostgresQueryBuilder);
+
+            applied = sqlx::query_as_with::<_, (i32,), _>(&sql, values)
+                .fetch_all(&mut *self.pool)
+                .await
+                .map(|rows| rows.into_iter().map(|r| r.0).collect())
+                .map_err(|_| ())?;
+        }
+
+        Ok(MIGRATIONS
+            .iter()
+            .map(|(version, _)| *version)
+            .filter(|version| !applied.contains(version))
+            .collect())
+    }
+}
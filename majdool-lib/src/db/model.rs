@@ -1,4 +1,4 @@
-use crate::api::{Media, MediaId};
+use crate::api::{DeadLetter, Media, MediaDetails, MediaId, MediaState, Retry};
 use sea_query::Iden;
 
 #[derive(Iden)]
@@ -10,6 +10,76 @@ pub enum MediaIndex {
     Hash,
     Synced,
     Lost,
+    LastVerifiedAt,
+    Verification,
+    OriginalName,
+    Source,
+    Kind,
+    Mime,
+}
+
+#[derive(Iden)]
+#[allow(dead_code)]
+pub enum MediaLabel {
+    Table,
+    Id,
+    MediaIndexId,
+    Label,
+}
+
+#[derive(Iden)]
+#[allow(dead_code)]
+pub enum DeadLetterQueue {
+    #[iden = "dead_letter"]
+    Table,
+    Id,
+    Retry,
+    Event,
+    Paths,
+    Reason,
+    Attempts,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+#[allow(dead_code)]
+pub enum DeviceImport {
+    Table,
+    Id,
+    Device,
+    Uuid,
+    Label,
+    MountPoint,
+    Files,
+    Bytes,
+    Failed,
+    Skipped,
+    StartedAt,
+    FinishedAt,
+}
+
+#[derive(Iden)]
+pub enum SourceCleanup {
+    Table,
+    Id,
+    MediaIndexId,
+    Action,
+    Source,
+    Destination,
+    FinishedAt,
+}
+
+#[derive(Iden)]
+pub enum Library {
+    Table,
+    Id,
+    Target,
+}
+
+#[derive(Iden)]
+pub enum SchemaMigration {
+    Table,
+    Version,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -30,3 +100,85 @@ impl From<MediaIndexView> for Media {
         }
     }
 }
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct MediaIndexStateView {
+    id: i64,
+    path: Option<String>,
+    hash: [u8; 32],
+    synced: bool,
+    lost: bool,
+}
+
+impl From<MediaIndexStateView> for MediaState {
+    fn from(value: MediaIndexStateView) -> Self {
+        Self {
+            id: MediaId { value: value.id },
+            path: value.path.map(|p| p.into()),
+            hash: value.hash,
+            synced: value.synced,
+            lost: value.lost,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct MediaDetailsView {
+    id: i64,
+    path: Option<String>,
+    hash: [u8; 32],
+    synced: bool,
+    lost: bool,
+    original_name: Option<String>,
+    source: Option<String>,
+    last_verified_at: Option<String>,
+    verification: Option<String>,
+    kind: Option<String>,
+    mime: Option<String>,
+}
+
+impl From<MediaDetailsView> for MediaDetails {
+    fn from(value: MediaDetailsView) -> Self {
+        Self {
+            state: MediaState {
+                id: MediaId { value: value.id },
+                path: value.path.map(|p| p.into()),
+                hash: value.hash,
+                synced: value.synced,
+                lost: value.lost,
+            },
+            original_name: value.original_name,
+            source: value.source,
+            last_verified_at: value.last_verified_at,
+            verification: value.verification,
+            kind: value.kind,
+            mime: value.mime,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct DeadLetterView {
+    id: i64,
+    retry: Option<String>,
+    event: String,
+    // A JSON array of the paths.
+    paths: String,
+    reason: String,
+    attempts: i32,
+    created_at: String,
+}
+
+impl From<DeadLetterView> for DeadLetter {
+    fn from(value: DeadLetterView) -> Self {
+        Self {
+            id: value.id,
+            retry: value.retry.as_deref().and_then(Retry::parse),
+            event: value.event,
+            paths: serde_json::from_str(&value.paths).unwrap_or_default(),
+            reason: value.reason,
+            attempts: value.attempts,
+            created_at: value.created_at,
+        }
+    }
+}
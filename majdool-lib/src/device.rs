@@ -0,0 +1,358 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const BY_UUID: &str = "/dev/disk/by-uuid";
+const BY_LABEL: &str = "/dev/disk/by-label";
+const SYS_CLASS_BLOCK: &str = "/sys/class/block";
+const UDEV_DATA: &str = "/run/udev/data";
+
+/// A removable block device mounted beneath the mount root (ex: an SD card at `/media/DCIM_CARD`).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Volume {
+    pub device: PathBuf,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+    pub mount_point: PathBuf,
+}
+
+impl Volume {
+    /// A human-readable name for the volume, preferring its label.
+    pub fn name(&self) -> String {
+        self.label
+            .clone()
+            .or_else(|| self.uuid.clone())
+            .unwrap_or_else(|| self.device.to_string_lossy().to_string())
+    }
+}
+
+/// The removable volumes currently mounted beneath the `mount_root` (ex: `/media` or `/run/media/<user>`).
+/// The devices holding any of the `exclude` paths (ex: the target and the sources) are never volumes, even when they're mounted beneath the mount root.
+pub async fn mounted_volumes(
+    mount_root: impl AsRef<Path>,
+    exclude: &[PathBuf],
+) -> Result<Vec<Volume>, std::io::Error> {
+    let mountinfo = tokio::fs::read_to_string(MOUNTINFO).await?;
+    // Not every system populates these (ex: containers), in which case the volumes are only known by device.
+    let uuids = read_device_links(BY_UUID).await.unwrap_or_default();
+    let labels = read_device_links(BY_LABEL).await.unwrap_or_default();
+    let mut volumes = Vec::default();
+
+    for (device, mount_point) in candidate_mounts(parse_mountinfo(&mountinfo), mount_root, exclude)
+    {
+        // The mountinfo may name the device through a symlink (ex: `/dev/mapper/..`).
+        let device = tokio::fs::canonicalize(&device).await.unwrap_or(device);
+
+        if !is_hotplug(SYS_CLASS_BLOCK, UDEV_DATA, &device).await {
+            continue;
+        }
+
+        volumes.push(Volume {
+            uuid: uuids.get(&device).cloned(),
+            label: labels.get(&device).cloned(),
+            device,
+            mount_point,
+        });
+    }
+
+    Ok(volumes)
+}
+
+/// The `mounts` beneath the `mount_root`, minus those of the devices holding any of the `exclude` paths.
+fn candidate_mounts(
+    mounts: Vec<(PathBuf, PathBuf)>,
+    mount_root: impl AsRef<Path>,
+    exclude: &[PathBuf],
+) -> Vec<(PathBuf, PathBuf)> {
+    let mount_root = mount_root.as_ref();
+    // A path is on the device of the deepest mount containing it.
+    let excluded: HashSet<PathBuf> = exclude
+        .iter()
+        .filter_map(|path| {
+            mounts
+                .iter()
+                .filter(|(_, mount_point)| path.starts_with(mount_point))
+                .max_by_key(|(_, mount_point)| mount_point.components().count())
+                .map(|(device, _)| device.clone())
+        })
+        .collect();
+
+    mounts
+        .into_iter()
+        .filter(|(device, mount_point)| {
+            mount_point != mount_root
+                && mount_point.starts_with(mount_root)
+                && !excluded.contains(device)
+        })
+        .collect()
+}
+
+/// Whether the block `device` is removable (ex: an SD card), or on a USB or MMC bus (ex: a USB SSD, which doesn't report itself as removable).
+/// Fixed disks are never imported, even when they're mounted beneath the mount root.
+async fn is_hotplug(
+    sys_class_block: impl AsRef<Path>,
+    udev_data: impl AsRef<Path>,
+    device: &Path,
+) -> bool {
+    let Some(name) = device.file_name() else {
+        return false;
+    };
+    let block = sys_class_block.as_ref().join(name);
+
+    // A partition's flag is on its disk, which is its parent in sysfs.
+    let disk = if tokio::fs::try_exists(block.join("partition"))
+        .await
+        .unwrap_or(false)
+    {
+        tokio::fs::canonicalize(&block)
+            .await
+            .ok()
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+    } else {
+        Some(block.clone())
+    };
+
+    if let Some(disk) = disk
+        && tokio::fs::read_to_string(disk.join("removable"))
+            .await
+            .is_ok_and(|removable| removable.trim() == "1")
+    {
+        return true;
+    }
+
+    let Ok(number) = tokio::fs::read_to_string(block.join("dev")).await else {
+        return false;
+    };
+
+    tokio::fs::read_to_string(udev_data.as_ref().join(format!("b{}", number.trim())))
+        .await
+        .is_ok_and(|data| {
+            data.lines()
+                .any(|line| matches!(line, "E:ID_BUS=usb" | "E:ID_BUS=mmc"))
+        })
+}
+
+/// Parse the `(device, mount point)` of each block device mount in a `/proc/<pid>/mountinfo`.
+fn parse_mountinfo(content: &str) -> Vec<(PathBuf, PathBuf)> {
+    let mut mounts = Vec::default();
+
+    for line in content.lines() {
+        // ID PARENT MAJOR:MINOR ROOT MOUNT_POINT OPTIONS [OPTIONAL..] - FSTYPE SOURCE SUPER_OPTIONS
+        let fields: Vec<&str> = line.split(' ').collect();
+        let Some(separator) = fields.iter().position(|field| *field == "-") else {
+            continue;
+        };
+
+        if let (Some(mount_point), Some(source)) = (fields.get(4), fields.get(separator + 2))
+            && source.starts_with("/dev/")
+        {
+            mounts.push((
+                PathBuf::from(unescape_mountinfo(source)),
+                PathBuf::from(unescape_mountinfo(mount_point)),
+            ));
+        }
+    }
+
+    mounts
+}
+
+/// The kernel escapes whitespace and backslashes in mountinfo as octal (ex: `\040` for a space).
+fn unescape_mountinfo(field: &str) -> String {
+    unescape(field, "\\", 3, 8)
+}
+
+/// Udev escapes unsafe characters in `/dev/disk/by-*` names as hex (ex: `\x20` for a space).
+fn unescape_udev(name: &str) -> String {
+    unescape(name, "\\x", 2, 16)
+}
+
+fn unescape(value: &str, prefix: &str, digits: usize, radix: u32) -> String {
+    let mut bytes = Vec::default();
+    let mut rest = value;
+
+    while let Some(index) = rest.find(prefix) {
+        bytes.extend_from_slice(&rest.as_bytes()[..index]);
+        let escaped = &rest[index + prefix.len()..];
+
+        match escaped
+            .get(..digits)
+            .and_then(|code| u8::from_str_radix(code, radix).ok())
+        {
+            Some(byte) => {
+                bytes.push(byte);
+                rest = &escaped[digits..];
+            }
+            None => {
+                bytes.extend_from_slice(prefix.as_bytes());
+                rest = escaped;
+            }
+        }
+    }
+
+    bytes.extend_from_slice(rest.as_bytes());
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Map each device to its name in a `/dev/disk/by-*` directory of symlinks.
+async fn read_device_links(
+    directory: impl AsRef<Path>,
+) -> Result<HashMap<PathBuf, String>, std::io::Error> {
+    let mut links = HashMap::default();
+    let mut entries = tokio::fs::read_dir(directory).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        if let Ok(device) = tokio::fs::canonicalize(entry.path()).await {
+            links.insert(device, unescape_udev(&entry.file_name().to_string_lossy()));
+        }
+    }
+
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_mountinfo_block_devices() {
+        let content = "\
+23 28 0:22 / /proc rw,relatime - proc proc rw
+28 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+97 28 8:17 / /media/NIKON\\040D750 rw,nosuid,nodev shared:52 - exfat /dev/sdb1 rw,fmask=0022
+";
+
+        assert_eq!(
+            parse_mountinfo(content),
+            vec![
+                (PathBuf::from("/dev/sda1"), PathBuf::from("/")),
+                (
+                    PathBuf::from("/dev/sdb1"),
+                    PathBuf::from("/media/NIKON D750")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn candidate_mounts_exclude_the_target() {
+        let mounts = vec![
+            (PathBuf::from("/dev/sda1"), PathBuf::from("/")),
+            (PathBuf::from("/dev/sdb1"), PathBuf::from("/media/library")),
+            (PathBuf::from("/dev/sdb1"), PathBuf::from("/media/bound")),
+            (PathBuf::from("/dev/sdc1"), PathBuf::from("/media/card")),
+            (
+                PathBuf::from("/dev/sdd1"),
+                PathBuf::from("/media/card/inner"),
+            ),
+            (PathBuf::from("/dev/sde1"), PathBuf::from("/media/phone")),
+        ];
+
+        assert_eq!(
+            candidate_mounts(
+                mounts,
+                "/media",
+                &[
+                    PathBuf::from("/media/library/target"),
+                    PathBuf::from("/media/card/inner/inbox"),
+                    PathBuf::from("/srv/inbox"),
+                ]
+            ),
+            vec![
+                (PathBuf::from("/dev/sdc1"), PathBuf::from("/media/card")),
+                (PathBuf::from("/dev/sde1"), PathBuf::from("/media/phone")),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hotplug_devices() {
+        let dir = tempdir().unwrap();
+        let sys = dir.path().join("sys");
+        let udev = dir.path().join("udev");
+        tokio::fs::create_dir_all(&udev).await.unwrap();
+
+        // (disk, removable, dev, udev bus)
+        for (disk, removable, dev, bus) in [
+            ("sdb", "1", "8:17", None),
+            ("sdc", "0", "8:33", Some("usb")),
+            ("sda", "0", "8:1", Some("ata")),
+        ] {
+            let partition = sys.join(format!("devices/{disk}/{disk}1"));
+            tokio::fs::create_dir_all(&partition).await.unwrap();
+            tokio::fs::write(sys.join(format!("devices/{disk}/removable")), removable)
+                .await
+                .unwrap();
+            tokio::fs::write(partition.join("partition"), "1")
+                .await
+                .unwrap();
+            tokio::fs::write(partition.join("dev"), format!("{dev}\n"))
+                .await
+                .unwrap();
+            tokio::fs::symlink(&partition, sys.join(format!("{disk}1")))
+                .await
+                .unwrap();
+
+            if let Some(bus) = bus {
+                tokio::fs::write(
+                    udev.join(format!("b{dev}")),
+                    format!("S:disk/by-id/x\nE:ID_BUS={bus}\n"),
+                )
+                .await
+                .unwrap();
+            }
+        }
+
+        let hotplug = async |device: &str| is_hotplug(&sys, &udev, Path::new(device)).await;
+        assert!(hotplug("/dev/sdb1").await);
+        assert!(hotplug("/dev/sdc1").await);
+        assert!(!hotplug("/dev/sda1").await);
+        assert!(!hotplug("/dev/sdz1").await);
+    }
+
+    #[test]
+    fn unescape_names() {
+        assert_eq!(unescape_mountinfo("a\\040b\\134c"), "a b\\c");
+        assert_eq!(unescape_udev("EOS\\x20DIGITAL"), "EOS DIGITAL");
+        assert_eq!(unescape_udev("trailing\\x2"), "trailing\\x2");
+    }
+
+    #[tokio::test]
+    async fn device_links() {
+        let dir = tempdir().unwrap();
+        let device = dir.path().join("sdb1");
+        tokio::fs::write(&device, b"").await.unwrap();
+        tokio::fs::create_dir(dir.path().join("by-label"))
+            .await
+            .unwrap();
+        tokio::fs::symlink(&device, dir.path().join("by-label/EOS\\x20DIGITAL"))
+            .await
+            .unwrap();
+
+        let links = read_device_links(dir.path().join("by-label"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            links,
+            HashMap::from([(device.canonicalize().unwrap(), "EOS DIGITAL".to_string())])
+        );
+    }
+
+    #[test]
+    fn volume_name() {
+        let mut volume = Volume {
+            device: PathBuf::from("/dev/sdb1"),
+            uuid: None,
+            label: None,
+            mount_point: PathBuf::from("/media/card"),
+        };
+        assert_eq!(volume.name(), "/dev/sdb1");
+
+        volume.uuid = Some("3A5B-1C2D".to_string());
+        assert_eq!(volume.name(), "3A5B-1C2D");
+
+        volume.label = Some("EOS DIGITAL".to_string());
+        assert_eq!(volume.name(), "EOS DIGITAL");
+    }
+}
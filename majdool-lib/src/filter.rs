@@ -0,0 +1,176 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::path::Path;
+
+/// The junk which operating systems and cameras leave on drives.
+const DEFAULT_EXCLUDE: [&str; 10] = [
+    ".DS_Store",
+    "._*",
+    ".Trashes",
+    ".Spotlight-V100",
+    ".fseventsd",
+    ".TemporaryItems",
+    "Thumbs.db",
+    "desktop.ini",
+    "System Volume Information",
+    "*.CTG",
+];
+
+/// Decides which files (of a source or drive) are flushed, by glob patterns and size bounds.
+/// The patterns are matched case-insensitively (drives are usually FAT), against the file name, the path within the root, and each directory on the way (so excluding `.Trashes` excludes everything beneath it).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Filter {
+    /// When given, only the files matching one of these are flushed.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default = "default_exclude")]
+    pub exclude: Vec<String>,
+    /// In bytes - by default, empty files (ex: temp files) are skipped.
+    #[serde(default = "default_min_size")]
+    pub min_size: u64,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    #[serde(skip)]
+    include_set: GlobSet,
+    #[serde(skip)]
+    exclude_set: GlobSet,
+}
+
+fn default_exclude() -> Vec<String> {
+    DEFAULT_EXCLUDE.iter().map(|p| p.to_string()).collect()
+}
+
+fn default_min_size() -> u64 {
+    1
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            include: Vec::default(),
+            exclude: default_exclude(),
+            min_size: default_min_size(),
+            max_size: None,
+            include_set: GlobSet::empty(),
+            exclude_set: GlobSet::empty(),
+        }
+        .compile()
+        // The default patterns are all valid.
+        .unwrap()
+    }
+}
+
+impl Filter {
+    /// A filter which accepts everything.
+    pub fn none() -> Self {
+        Self {
+            include: Vec::default(),
+            exclude: Vec::default(),
+            min_size: 0,
+            max_size: None,
+            include_set: GlobSet::empty(),
+            exclude_set: GlobSet::empty(),
+        }
+    }
+
+    /// Build the glob sets (after deserializing, or changing the patterns).
+    pub fn compile(mut self) -> Result<Self, String> {
+        self.include_set = glob_set(&self.include)?;
+        self.exclude_set = glob_set(&self.exclude)?;
+        Ok(self)
+    }
+
+    /// This filter, additionally excluding the `patterns`.
+    pub fn with_excludes(&self, patterns: &[String]) -> Result<Self, String> {
+        let mut filter = self.clone();
+        filter.exclude.extend(patterns.iter().cloned());
+        filter.compile()
+    }
+
+    /// Whether the file at `path` (beneath the `root`) is excluded by the patterns.
+    pub fn excludes(&self, root: impl AsRef<Path>, path: impl AsRef<Path>) -> bool {
+        let path = path.as_ref();
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let name = path.file_name().unwrap_or_default();
+        let excluded = self.exclude_set.is_match(name)
+            || self.exclude_set.is_match(relative)
+            || relative.parent().is_some_and(|directory| {
+                directory
+                    .iter()
+                    .any(|component| self.exclude_set.is_match(component))
+            });
+        let included = self.include.is_empty()
+            || self.include_set.is_match(name)
+            || self.include_set.is_match(relative);
+        excluded || !included
+    }
+
+    pub fn accepts_size(&self, size: u64) -> bool {
+        size >= self.min_size && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Whether the file at `path` (beneath the `root`), of `size` bytes, should be flushed.
+    pub fn accepts(&self, root: impl AsRef<Path>, path: impl AsRef<Path>, size: u64) -> bool {
+        self.accepts_size(size) && !self.excludes(root, path)
+    }
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+        builder.add(glob);
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_excludes() {
+        let filter = Filter::default();
+        let accepts = |path: &str, size| filter.accepts("/media/card", path, size);
+
+        assert!(accepts("/media/card/DCIM/100CANON/IMG_0001.JPG", 10));
+        assert!(!accepts("/media/card/DCIM/100CANON/IMG_0001.JPG", 0));
+        assert!(!accepts("/media/card/.DS_Store", 10));
+        assert!(!accepts("/media/card/DCIM/._IMG_0001.JPG", 10));
+        assert!(!accepts("/media/card/.Trashes/501/IMG_0002.JPG", 10));
+        assert!(!accepts("/media/card/DCIM/thumbs.db", 10));
+        assert!(!accepts("/media/card/DCIM/CANONMSC/M0100.ctg", 10));
+        assert!(!accepts(
+            "/media/card/System Volume Information/IndexerVolumeGuid",
+            10
+        ));
+        assert!(Filter::none().accepts("/media/card", "/media/card/.DS_Store", 0));
+    }
+
+    #[test]
+    fn include_and_size_bounds() {
+        let filter = Filter {
+            include: vec!["*.jpg".to_string(), "DCIM/**/*.mp4".to_string()],
+            max_size: Some(100),
+            ..Filter::default()
+        }
+        .compile()
+        .unwrap()
+        .with_excludes(&["*_preview.*".to_string()])
+        .unwrap();
+        let accepts = |path: &str, size| filter.accepts("/media/card", path, size);
+
+        assert!(accepts("/media/card/a.JPG", 100));
+        assert!(!accepts("/media/card/a.jpg", 101));
+        assert!(accepts("/media/card/DCIM/100/b.mp4", 10));
+        assert!(!accepts("/media/card/MISC/b.mp4", 10));
+        assert!(!accepts("/media/card/a_preview.jpg", 10));
+        assert!(!accepts("/media/card/.DS_Store", 10));
+    }
+}
@@ -1,3 +1,7 @@
+pub mod cleanup;
 pub mod filesystem;
 pub mod fsutil;
 mod model;
+pub mod receipt;
+pub mod sidecar;
+pub mod sniff;
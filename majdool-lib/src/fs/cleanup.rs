@@ -0,0 +1,181 @@
+use crate::api::Cleaned;
+use crate::fs::fsutil::content_wise_equals;
+use std::path::Path;
+
+/// Clean up the `source` file whose target copy is at `copy` (deleting it, or moving it aside), as per the `cleanup`.
+/// The source is only touched once it's re-verified byte-for-byte against the copy, and once the cleanup is `record`-ed.
+/// On any failure (and in a `dry_run`, which records nothing), the source is kept as is.
+pub async fn clean_up_source(
+    source: &Path,
+    copy: &Path,
+    cleanup: &Cleaned,
+    dry_run: bool,
+    record: impl AsyncFnOnce() -> Result<(), ()>,
+) -> Result<Cleaned, ()> {
+    if matches!(cleanup, Cleaned::Kept | Cleaned::DryRun) {
+        return Ok(Cleaned::Kept);
+    }
+
+    if !content_wise_equals(source, copy).await.map_err(|_| ())? {
+        return Err(());
+    }
+
+    if dry_run {
+        return Ok(Cleaned::DryRun);
+    }
+
+    record().await?;
+
+    match cleanup {
+        Cleaned::Deleted => tokio::fs::remove_file(source).await.map_err(|_| ())?,
+        Cleaned::Moved(destination) => {
+            if let Some(parent) = destination.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(|_| ())?;
+            }
+
+            // Unlike a rename, linking never replaces the destination (and there's no window between checking for it and moving).
+            tokio::fs::hard_link(source, destination)
+                .await
+                .map_err(|_| ())?;
+
+            if tokio::fs::remove_file(source).await.is_err() {
+                let _ = tokio::fs::remove_file(destination).await;
+                return Err(());
+            }
+        }
+        Cleaned::Kept | Cleaned::DryRun => {}
+    }
+
+    Ok(cleanup.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// A source file and its (matching) target copy.
+    async fn flushed(dir: &Path) -> (std::path::PathBuf, std::path::PathBuf) {
+        let source = dir.join("source/DCIM/IMG_0001.jpg");
+        let copy = dir.join("target/000000000000002a.jpg");
+        tokio::fs::create_dir_all(source.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(copy.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&source, b"hello").await.unwrap();
+        tokio::fs::write(&copy, b"hello").await.unwrap();
+        (source, copy)
+    }
+
+    #[tokio::test]
+    async fn deletes_once_verified_and_recorded() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+        let mut recorded = false;
+
+        let cleaned = clean_up_source(&source, &copy, &Cleaned::Deleted, false, async || {
+            // The source is still there when the cleanup is recorded.
+            assert!(source.exists());
+            recorded = true;
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(cleaned.as_str(), "deleted");
+        assert!(recorded);
+        assert!(!source.exists());
+        assert!(copy.exists());
+    }
+
+    #[tokio::test]
+    async fn moves_once_verified_and_recorded() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+        let destination = dir.path().join("source/.imported/DCIM/IMG_0001.jpg");
+        let cleanup = Cleaned::Moved(destination.clone());
+
+        let cleaned = clean_up_source(&source, &copy, &cleanup, false, async || Ok(()))
+            .await
+            .unwrap();
+
+        assert_eq!(cleaned.as_str(), "moved");
+        assert!(!source.exists());
+        assert_eq!(tokio::fs::read(&destination).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn dry_run_records_and_touches_nothing() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+
+        let cleaned = clean_up_source(&source, &copy, &Cleaned::Deleted, true, async || {
+            panic!("a dry run isn't recorded")
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(cleaned.as_str(), "dry-run");
+        assert!(source.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_verification_keeps_the_source() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+        tokio::fs::write(&copy, b"hellp").await.unwrap();
+
+        assert!(
+            clean_up_source(&source, &copy, &Cleaned::Deleted, false, async || {
+                panic!("a failed verification isn't recorded")
+            })
+            .await
+            .is_err()
+        );
+        assert!(source.exists());
+
+        // Likewise when the copy is gone altogether.
+        tokio::fs::remove_file(&copy).await.unwrap();
+        assert!(
+            clean_up_source(&source, &copy, &Cleaned::Deleted, false, async || Ok(()))
+                .await
+                .is_err()
+        );
+        assert!(source.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_record_keeps_the_source() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+
+        assert!(
+            clean_up_source(&source, &copy, &Cleaned::Deleted, false, async || Err(()))
+                .await
+                .is_err()
+        );
+        assert!(source.exists());
+    }
+
+    #[tokio::test]
+    async fn existing_destination_is_never_replaced() {
+        let dir = tempdir().unwrap();
+        let (source, copy) = flushed(dir.path()).await;
+        let destination = dir.path().join("source/.imported/DCIM/IMG_0001.jpg");
+        tokio::fs::create_dir_all(destination.parent().unwrap())
+            .await
+            .unwrap();
+        tokio::fs::write(&destination, b"earlier").await.unwrap();
+        let cleanup = Cleaned::Moved(destination.clone());
+
+        assert!(
+            clean_up_source(&source, &copy, &cleanup, false, async || Ok(()))
+                .await
+                .is_err()
+        );
+        assert!(source.exists());
+        assert_eq!(tokio::fs::read(&destination).await.unwrap(), b"earlier");
+    }
+}
@@ -1,25 +1,469 @@
-use crate::api::MediaId;
-use crate::fs::fsutil::copy_file;
-use std::ffi::OsStr;
+use crate::api::{Media, MediaId};
+use crate::fs::fsutil::{FileHash, cache_file_hash, copy_file};
+use crate::fs::sidecar::{Sidecar, read_sidecar, sidecar_path, write_sidecar};
+use crate::fs::sniff::{FileType, normalized_extension};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-const FLUSH: &'static str = "flush/";
-const DEFAULT_EXTENSION: &'static str = "unk";
+pub const FLUSH_DIRECTORY: &str = "flush";
 
+/// How the flush directory is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Every media directly in `flush/`.
+    #[default]
+    Flat,
+    /// Two levels of directories, from the id's hex digits before its last two (ex: `flush/00/00/000000000000002a.jpg`).
+    /// For the first 2^24 ids, this keeps each directory to at most 256 media (and to another 256 for every further 2^24 ids),
+    /// so that listing it (and recomputing its merkle index) stays fast.
+    Sharded,
+}
+
+impl Layout {
+    /// The directory (relative to the target) which the media `id` is flushed into.
+    pub fn flush_directory(&self, id: MediaId) -> PathBuf {
+        let directory = PathBuf::from(FLUSH_DIRECTORY);
+
+        match self {
+            Layout::Flat => directory,
+            Layout::Sharded => {
+                let file_base = id.file_base();
+                directory.join(&file_base[10..12]).join(&file_base[12..14])
+            }
+        }
+    }
+}
+
+/// The media which are being flushed, so that a watcher of the target can tell its own copies from unsanctioned writes.
+/// Every `MediaFilesystem` flushing into the watched target should share the same `FlushWrites`.
+#[derive(Clone, Default)]
+pub struct FlushWrites {
+    paths: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl FlushWrites {
+    fn start(&self, path: &Path) {
+        self.paths.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    fn abandon(&self, path: &Path) {
+        self.paths.lock().unwrap().remove(path);
+    }
+
+    /// Whether the write to `path` which just completed was a flush's.
+    /// This only answers once per flush - any later write to the path is someone else's.
+    pub fn take(&self, path: &Path) -> bool {
+        self.paths.lock().unwrap().remove(path)
+    }
+}
+
+#[derive(Clone)]
 pub struct MediaFilesystem {
     root: PathBuf,
+    layout: Layout,
+    sidecars: bool,
+    hash_cache: bool,
+    flush_writes: Option<FlushWrites>,
 }
 
 impl MediaFilesystem {
-    pub async fn flush_write(&self, source: impl AsRef<Path>, id: MediaId) -> Result<(), ()> {
-        // We need to manually retain the extension for the file, because we're writing it to a path based off its Id (not its source name).
-        let extension = source
-            .as_ref()
-            .extension()
-            .unwrap_or(&OsStr::new(DEFAULT_EXTENSION));
-        let mut destination = self.root.join(FLUSH).join(id.file_base());
-        destination.set_extension(&extension);
-        copy_file(source, destination).await.map_err(|_| ())?;
-        Ok(())
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            layout: Layout::default(),
+            sidecars: false,
+            hash_cache: false,
+            flush_writes: None,
+        }
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Write a `$ID.majdool.json` sidecar next to each media.
+    pub fn with_sidecars(mut self) -> Self {
+        self.sidecars = true;
+        self
+    }
+
+    /// Seed the hash cache of each flushed media (see `HashCache`).
+    pub fn with_hash_cache(mut self) -> Self {
+        self.hash_cache = true;
+        self
+    }
+
+    /// Register each flush's copy in the `flush_writes` while it's being written.
+    pub fn with_flush_writes(mut self, flush_writes: FlushWrites) -> Self {
+        self.flush_writes = Some(flush_writes);
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// The media is named by its id, with the extension of its sniffed `file_type` (see `normalized_extension`).
+    pub async fn flush_write(
+        &self,
+        source: impl AsRef<Path>,
+        id: MediaId,
+        hash: &FileHash,
+        file_type: Option<&FileType>,
+    ) -> Result<PathBuf, ()> {
+        // We need to manually set the extension for the file, because we're writing it to a path based off its Id (not its source name).
+        let directory = self.root.join(self.layout.flush_directory(id));
+        let mut destination = directory.join(id.file_base());
+        destination.set_extension(normalized_extension(&source, file_type));
+
+        if self.layout == Layout::Sharded {
+            tokio::fs::create_dir_all(&directory)
+                .await
+                .map_err(|_| ())?;
+        }
+
+        if let Some(flush_writes) = &self.flush_writes {
+            flush_writes.start(&destination);
+        }
+
+        if copy_file(&source, &destination).await.is_err() {
+            if let Some(flush_writes) = &self.flush_writes {
+                flush_writes.abandon(&destination);
+            }
+
+            return Err(());
+        }
+
+        if self.sidecars {
+            // A flush without its sidecar would lose the metadata, so it fails (and the copy, which is ours alone, is removed).
+            let sidecar = Sidecar::new(id, hash, Some(source.as_ref()));
+            if write_sidecar(sidecar_path(&destination), &sidecar)
+                .await
+                .is_err()
+            {
+                let _ = tokio::fs::remove_file(&destination).await;
+                return Err(());
+            }
+        }
+
+        if self.hash_cache {
+            // The cache is only an optimization, so failing to seed it doesn't fail the flush.
+            let _ = cache_file_hash(&destination, hash).await;
+        }
+
+        Ok(destination)
+    }
+
+    /// Rename the file at `path` (already on the target) to the media's name, in its current directory, returning its new path.
+    /// This is the in-place counterpart of `flush_write`, for adopting an existing library.
+    /// An `Ok` doesn't guarantee the sidecar was written (see `undo_rename`) - only where the media is.
+    pub async fn adopt_write(
+        &self,
+        path: impl AsRef<Path>,
+        id: MediaId,
+        hash: &FileHash,
+        file_type: Option<&FileType>,
+    ) -> Result<PathBuf, ()> {
+        let path = path.as_ref();
+        let mut destination = path.with_file_name(id.file_base());
+        destination.set_extension(normalized_extension(path, file_type));
+
+        // Renames silently replace the destination, so we must check for it ourselves.
+        if tokio::fs::try_exists(&destination).await.map_err(|_| ())? {
+            return Err(());
+        }
+
+        tokio::fs::rename(path, &destination)
+            .await
+            .map_err(|_| ())?;
+
+        if self.sidecars {
+            let sidecar = Sidecar::new(id, hash, Some(path));
+            if write_sidecar(sidecar_path(&destination), &sidecar)
+                .await
+                .is_err()
+            {
+                return undo_rename(path, destination).await;
+            }
+        }
+
+        if self.hash_cache {
+            let _ = cache_file_hash(&destination, hash).await;
+        }
+
+        Ok(destination)
+    }
+
+    /// Move the media (and its sidecar) into the `directory`, returning its new path.
+    /// An `Ok` doesn't guarantee the sidecar moved along (see `undo_rename`) - only where the media is.
+    pub async fn move_write(
+        &self,
+        media: &Media,
+        directory: impl AsRef<Path>,
+    ) -> Result<PathBuf, ()> {
+        let destination = directory.as_ref().join(media.path.file_name().ok_or(())?);
+
+        // Renames silently replace the destination, so we must check for it ourselves.
+        if tokio::fs::try_exists(&destination).await.map_err(|_| ())? {
+            return Err(());
+        }
+
+        tokio::fs::create_dir_all(directory.as_ref())
+            .await
+            .map_err(|_| ())?;
+        tokio::fs::rename(&media.path, &destination)
+            .await
+            .map_err(|_| ())?;
+
+        match tokio::fs::rename(sidecar_path(&media.path), sidecar_path(&destination)).await {
+            Ok(_) => Ok(destination),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(destination),
+            Err(_) => undo_rename(&media.path, destination).await,
+        }
+    }
+
+    /// Rewrite the labels in the media's sidecar (creating the sidecar if it doesn't exist yet).
+    pub async fn label_write(&self, media: &Media, labels: Vec<String>) -> Result<(), ()> {
+        if !self.sidecars {
+            return Ok(());
+        }
+
+        let path = sidecar_path(&media.path);
+        let mut sidecar = match read_sidecar(&path).await {
+            Ok(sidecar) => sidecar,
+            Err(e) if e.kind() == ErrorKind::NotFound => Sidecar::for_media(media),
+            Err(_) => return Err(()),
+        };
+        sidecar.labels = labels;
+        write_sidecar(&path, &sidecar).await.map_err(|_| ())
+    }
+}
+
+/// Move the media at `destination` back to its `path`, after its sidecar couldn't follow it (returning an `Err`, as nothing changed).
+/// If even that fails, this returns `Ok(destination)` - which is NOT a success, but where the media now is, for the index to follow.
+/// In that case the sidecar is left behind (missing, or at the old path), until relabeling the media rewrites it.
+async fn undo_rename(path: &Path, destination: PathBuf) -> Result<PathBuf, ()> {
+    match tokio::fs::rename(&destination, path).await {
+        Ok(_) => Err(()),
+        Err(_) => Ok(destination),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::sniff::sniff_file;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn flush_write_with_sidecar() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("IMG_0001.JPEG");
+        tokio::fs::write(&source, b"\xFF\xD8\xFF\xE0hello")
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join("target/flush"))
+            .await
+            .unwrap();
+        let filesystem = MediaFilesystem::new(dir.path().join("target")).with_sidecars();
+        let sniffed = sniff_file(&source).await.unwrap();
+
+        let destination = filesystem
+            .flush_write(&source, MediaId::new(42), &[0u8; 32], sniffed.as_ref())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            destination,
+            dir.path().join("target/flush/000000000000002a.jpg")
+        );
+        assert_eq!(
+            tokio::fs::read(&destination).await.unwrap(),
+            b"\xFF\xD8\xFF\xE0hello"
+        );
+        let sidecar = read_sidecar(sidecar_path(&destination)).await.unwrap();
+        assert_eq!(sidecar.id, 42);
+        assert_eq!(sidecar.original_name.as_deref(), Some("IMG_0001.JPEG"));
+    }
+
+    #[tokio::test]
+    async fn flush_write_sharded() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("IMG_0001.jpg");
+        tokio::fs::write(&source, b"hello").await.unwrap();
+        let filesystem = MediaFilesystem::new(dir.path()).with_layout(Layout::Sharded);
+
+        let destination = filesystem
+            .flush_write(&source, MediaId::new(0x123456), &[0u8; 32], None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            destination,
+            dir.path().join("flush/12/34/0000000000123456.jpg")
+        );
+        assert_eq!(tokio::fs::read(&destination).await.unwrap(), b"hello");
+        assert_eq!(
+            Layout::Sharded.flush_directory(MediaId::new(42)),
+            PathBuf::from("flush/00/00")
+        );
+        assert_eq!(
+            Layout::Flat.flush_directory(MediaId::new(0x123456)),
+            PathBuf::from("flush")
+        );
+    }
+
+    #[tokio::test]
+    async fn adopt_write_with_sidecar() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("2019/IMG_0001.jpg");
+        tokio::fs::create_dir_all(dir.path().join("2019"))
+            .await
+            .unwrap();
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let filesystem = MediaFilesystem::new(dir.path()).with_sidecars();
+
+        let destination = filesystem
+            .adopt_write(&path, MediaId::new(42), &[0u8; 32], None)
+            .await
+            .unwrap();
+
+        assert_eq!(destination, dir.path().join("2019/000000000000002a.jpg"));
+        assert!(!path.exists());
+        assert_eq!(tokio::fs::read(&destination).await.unwrap(), b"hello");
+        let sidecar = read_sidecar(sidecar_path(&destination)).await.unwrap();
+        assert_eq!(sidecar.original_name.as_deref(), Some("IMG_0001.jpg"));
+
+        // The destination is never replaced.
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        assert!(
+            filesystem
+                .adopt_write(&path, MediaId::new(42), &[0u8; 32], None)
+                .await
+                .is_err()
+        );
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn flush_write_registers_the_copy() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("IMG_0001.jpg");
+        tokio::fs::write(&source, b"hello").await.unwrap();
+        tokio::fs::create_dir_all(dir.path().join("target/flush"))
+            .await
+            .unwrap();
+        let flush_writes = FlushWrites::default();
+        let filesystem =
+            MediaFilesystem::new(dir.path().join("target")).with_flush_writes(flush_writes.clone());
+
+        let destination = filesystem
+            .flush_write(&source, MediaId::new(42), &[0u8; 32], None)
+            .await
+            .unwrap();
+
+        // Only the flush's own write is recognized, and only once.
+        assert!(flush_writes.take(&destination));
+        assert!(!flush_writes.take(&destination));
+
+        // A failed copy is forgotten right away (ex: the destination already exists).
+        assert!(
+            filesystem
+                .flush_write(&source, MediaId::new(42), &[0u8; 32], None)
+                .await
+                .is_err()
+        );
+        assert!(!flush_writes.take(&destination));
+    }
+
+    #[tokio::test]
+    async fn move_write_with_sidecar() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("flush/000000000000002a.jpg");
+        tokio::fs::create_dir_all(dir.path().join("flush"))
+            .await
+            .unwrap();
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let media = Media {
+            id: MediaId::new(42),
+            path: path.clone(),
+            hash: [0u8; 32],
+        };
+        let filesystem = MediaFilesystem::new(dir.path()).with_sidecars();
+        filesystem
+            .label_write(&media, vec!["dog".to_string()])
+            .await
+            .unwrap();
+
+        let destination = filesystem
+            .move_write(&media, dir.path().join("dogs"))
+            .await
+            .unwrap();
+
+        assert_eq!(destination, dir.path().join("dogs/000000000000002a.jpg"));
+        assert!(!path.exists());
+        assert!(!sidecar_path(&path).exists());
+        let sidecar = read_sidecar(sidecar_path(&destination)).await.unwrap();
+        assert_eq!(sidecar.labels, vec!["dog".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn failed_sidecar_leaves_media_in_place() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("flush/000000000000002a.jpg");
+        tokio::fs::create_dir_all(dir.path().join("flush"))
+            .await
+            .unwrap();
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let media = Media {
+            id: MediaId::new(42),
+            path: path.clone(),
+            hash: [0u8; 32],
+        };
+        let filesystem = MediaFilesystem::new(dir.path()).with_sidecars();
+        filesystem
+            .label_write(&media, vec!["dog".to_string()])
+            .await
+            .unwrap();
+
+        // A (non-empty) directory in the way of the sidecar fails its rename, so the media's is undone.
+        let blocked = dir.path().join("dogs/000000000000002a.majdool.json");
+        tokio::fs::create_dir_all(blocked.join("in-the-way"))
+            .await
+            .unwrap();
+        assert!(
+            filesystem
+                .move_write(&media, dir.path().join("dogs"))
+                .await
+                .is_err()
+        );
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"hello");
+        assert!(sidecar_path(&path).exists());
+        assert!(!dir.path().join("dogs/000000000000002a.jpg").exists());
+
+        // Likewise, a flush whose sidecar can't be written removes its copy.
+        let source = dir.path().join("IMG_0002.jpg");
+        tokio::fs::write(&source, b"hello").await.unwrap();
+        let blocked = dir.path().join("flush/000000000000002b.majdool.json");
+        tokio::fs::create_dir_all(blocked.join("in-the-way"))
+            .await
+            .unwrap();
+        assert!(
+            filesystem
+                .flush_write(&source, MediaId::new(43), &[0u8; 32], None)
+                .await
+                .is_err()
+        );
+        assert!(!dir.path().join("flush/000000000000002b.jpg").exists());
+        assert!(source.exists());
     }
 }
@@ -1,16 +1,140 @@
 use crate::fs::model::StreamComparator;
 use sha2::{Digest, Sha256};
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 pub type FileHash = [u8; 32];
 
+const HASH_CACHE_ATTRIBUTE: &str = "user.majdool.sha256";
+
+/// How hashing uses the hash cached in the file's extended attributes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashCache {
+    /// Always compute the hash, ignoring the cache.
+    #[default]
+    Disabled,
+    /// Trust the cached hash while the file's `(mtime, size, inode)` is unchanged.
+    Enabled,
+    /// Trust the cached hash like `Enabled`, but never write the cache (for readers which must leave the files untouched).
+    ReadOnly,
+    /// Always compute the hash, refreshing the cache.
+    Rehash,
+}
+
 pub async fn compute_file_hash(path: impl AsRef<Path>) -> Result<FileHash, std::io::Error> {
     let file = File::open(path).await?;
     compute_hash(file).await
 }
 
+/// Computes the file hash, reading and writing the hash cache as directed by `cache`.
+/// The cache is best effort: filesystems without extended attribute support simply always compute the hash.
+pub async fn compute_cached_file_hash(
+    path: impl AsRef<Path>,
+    cache: HashCache,
+) -> Result<FileHash, std::io::Error> {
+    if cache == HashCache::Disabled {
+        return compute_file_hash(path).await;
+    }
+
+    let path = path.as_ref();
+    let stat = CacheStat::read(path).await?;
+
+    if matches!(cache, HashCache::Enabled | HashCache::ReadOnly)
+        && let Some(hash) = read_hash_cache(path, &stat).await
+    {
+        return Ok(hash);
+    }
+
+    let hash = compute_file_hash(path).await?;
+
+    if cache == HashCache::ReadOnly {
+        return Ok(hash);
+    }
+
+    // Don't cache the hash of a file which changed while we were reading it.
+    if CacheStat::read(path).await? == stat {
+        let _ = write_hash_cache(path, &stat, &hash).await;
+    }
+
+    Ok(hash)
+}
+
+/// Caches an already known hash for the file (ex: immediately after copying it).
+pub async fn cache_file_hash(
+    path: impl AsRef<Path>,
+    hash: &FileHash,
+) -> Result<(), std::io::Error> {
+    let stat = CacheStat::read(path.as_ref()).await?;
+    write_hash_cache(path.as_ref(), &stat, hash).await
+}
+
+/// The stat tuple under which a cached hash remains valid.
+#[derive(Debug, PartialEq, Eq)]
+struct CacheStat {
+    mtime: i64,
+    mtime_nsec: i64,
+    size: u64,
+    inode: u64,
+}
+
+impl CacheStat {
+    async fn read(path: &Path) -> Result<Self, std::io::Error> {
+        let metadata = tokio::fs::metadata(path).await?;
+        Ok(Self {
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+            size: metadata.size(),
+            inode: metadata.ino(),
+        })
+    }
+
+    fn key(&self) -> String {
+        format!(
+            "{}.{} {} {}",
+            self.mtime, self.mtime_nsec, self.size, self.inode
+        )
+    }
+
+    fn encode(&self, hash: &FileHash) -> String {
+        format!("{} {}", hex::encode(hash), self.key())
+    }
+
+    fn decode(&self, value: &[u8]) -> Option<FileHash> {
+        let (hash, key) = std::str::from_utf8(value).ok()?.split_once(' ')?;
+
+        if key != self.key() {
+            return None;
+        }
+
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(hash, &mut bytes).ok()?;
+        Some(bytes)
+    }
+}
+
+async fn read_hash_cache(path: &Path, stat: &CacheStat) -> Option<FileHash> {
+    let path = path.to_path_buf();
+    let value = tokio::task::spawn_blocking(move || xattr::get(path, HASH_CACHE_ATTRIBUTE))
+        .await
+        .ok()?
+        .ok()??;
+    stat.decode(&value)
+}
+
+async fn write_hash_cache(
+    path: &Path,
+    stat: &CacheStat,
+    hash: &FileHash,
+) -> Result<(), std::io::Error> {
+    let path = path.to_path_buf();
+    let value = stat.encode(hash);
+    tokio::task::spawn_blocking(move || xattr::set(path, HASH_CACHE_ATTRIBUTE, value.as_bytes()))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
 async fn compute_hash<R: AsyncRead + Unpin>(mut reader: R) -> Result<FileHash, std::io::Error> {
     let mut hasher = Sha256::new();
     let mut buffer = [0; 8192];
@@ -26,10 +150,40 @@ async fn compute_hash<R: AsyncRead + Unpin>(mut reader: R) -> Result<FileHash, s
     Ok(hasher.finalize().into())
 }
 
+/// The ways `copy_file` can copy a file, from the fastest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CopyMethod {
+    /// A reflink (`FICLONE`), sharing the source's extents - only within a Btrfs or XFS volume (or the like).
+    Clone,
+    /// `copy_file_range`, which copies within the kernel (or server-side, on NFS/SMB).
+    CopyRange,
+    /// Through a userspace buffer, which works anywhere.
+    Buffered,
+}
+
+const COPY_METHODS: [CopyMethod; 3] = [
+    CopyMethod::Clone,
+    CopyMethod::CopyRange,
+    CopyMethod::Buffered,
+];
+
+/// Copy the `source` into a new file at `target` (which must not exist), returning the bytes copied.
+/// The fastest copy which the filesystems support is used (see `CopyMethod`), falling back to a buffered copy.
 pub async fn copy_file(
     source: impl AsRef<Path>,
     target: impl AsRef<Path>,
 ) -> Result<u64, std::io::Error> {
+    copy_file_with(source, target, &COPY_METHODS)
+        .await
+        .map(|(total, _)| total)
+}
+
+/// Copy with the first of the `methods` which is supported, returning the bytes copied and the method used.
+async fn copy_file_with(
+    source: impl AsRef<Path>,
+    target: impl AsRef<Path>,
+    methods: &[CopyMethod],
+) -> Result<(u64, CopyMethod), std::io::Error> {
     let mut source_file = File::open(source).await?;
     let mut target_file = OpenOptions::new()
         .write(true)
@@ -37,21 +191,163 @@ pub async fn copy_file(
         .open(target)
         .await?;
 
+    for method in methods {
+        let copied = match method {
+            CopyMethod::Clone => clone_file(source_file, target_file).await?,
+            CopyMethod::CopyRange => copy_file_range(source_file, target_file).await?,
+            CopyMethod::Buffered => {
+                return buffered_copy(&mut source_file, &mut target_file)
+                    .await
+                    .map(|total| (total, CopyMethod::Buffered));
+            }
+        };
+
+        match copied {
+            Copied::Done(total) => return Ok((total, *method)),
+            Copied::Unsupported(source, target) => (source_file, target_file) = (source, target),
+        }
+    }
+
+    Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+}
+
+/// The outcome of a fast copy, which hands the files back when it isn't supported (and nothing was copied).
+enum Copied {
+    Done(u64),
+    Unsupported(File, File),
+}
+
+/// Whether the error means that the fast copy isn't supported between these files (ex: across filesystems), rather than a failure.
+#[cfg(target_os = "linux")]
+fn is_unsupported(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::ENOTTY | libc::EXDEV | libc::EINVAL | libc::ENOSYS)
+    )
+}
+
+#[cfg(target_os = "linux")]
+async fn clone_file(source: File, target: File) -> Result<Copied, std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let source = source.into_std().await;
+    let target = target.into_std().await;
+    let length = source.metadata()?.len();
+
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: both descriptors are open for the duration of the call.
+        let result = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+
+        if result == 0 {
+            return Ok(Copied::Done(length));
+        }
+
+        match std::io::Error::last_os_error() {
+            e if is_unsupported(&e) => Ok(Copied::Unsupported(
+                File::from_std(source),
+                File::from_std(target),
+            )),
+            e => Err(e),
+        }
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+#[cfg(target_os = "linux")]
+async fn copy_file_range(source: File, target: File) -> Result<Copied, std::io::Error> {
+    use std::os::fd::AsRawFd;
+
+    let source = source.into_std().await;
+    let target = target.into_std().await;
+
+    tokio::task::spawn_blocking(move || {
+        let mut total = 0u64;
+
+        loop {
+            // SAFETY: both descriptors are open for the duration of the call, and the null offsets use (and advance) the files' own.
+            let n = unsafe {
+                libc::copy_file_range(
+                    source.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    target.as_raw_fd(),
+                    std::ptr::null_mut(),
+                    1 << 30,
+                    0,
+                )
+            };
+
+            match n {
+                0 => return Ok(Copied::Done(total)),
+                n if n > 0 => total += n as u64,
+                _ => match std::io::Error::last_os_error() {
+                    // Only fall back while the files' offsets are untouched.
+                    e if total == 0 && is_unsupported(&e) => {
+                        return Ok(Copied::Unsupported(
+                            File::from_std(source),
+                            File::from_std(target),
+                        ));
+                    }
+                    e if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    e => return Err(e),
+                },
+            }
+        }
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn clone_file(source: File, target: File) -> Result<Copied, std::io::Error> {
+    Ok(Copied::Unsupported(source, target))
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn copy_file_range(source: File, target: File) -> Result<Copied, std::io::Error> {
+    Ok(Copied::Unsupported(source, target))
+}
+
+async fn buffered_copy(source: &mut File, target: &mut File) -> Result<u64, std::io::Error> {
     let mut buffer = [0; 8192];
     let mut total = 0;
 
     loop {
-        let n = source_file.read(&mut buffer).await?;
+        let n = source.read(&mut buffer).await?;
         if n == 0 {
             break;
         }
-        target_file.write_all(&buffer[..n]).await?;
+        target.write_all(&buffer[..n]).await?;
         total += n as u64;
     }
 
     Ok(total)
 }
 
+/// Recursively lists all the files under `root`.
+/// Symlinks are not followed.
+pub async fn walk_files(root: impl AsRef<Path>) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut files = Vec::default();
+    let mut directories = vec![root.as_ref().to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(&directory).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
 /// Performs content wise comparison of the two paths.
 /// If the content exactly matches, return true.
 /// Otherwise, false.
@@ -77,7 +373,7 @@ pub async fn content_wise_equals(
                         left_tx.send(buffer_a[..n_a].to_vec()).await.unwrap();
                         a_done = n_a == 0;
                     }
-                    Err(e) => {
+                    Err(_) => {
                         // TODO: handle the error
                         break;
                     }
@@ -90,7 +386,7 @@ pub async fn content_wise_equals(
                         right_tx.send(buffer_b[..n_b].to_vec()).await.unwrap();
                         b_done = n_b == 0;
                     }
-                    Err(e) => {
+                    Err(_) => {
                         // TODO: handle the error
                         break;
                     }
@@ -154,11 +450,137 @@ mod tests {
         let dst = dir.path().join("dst.txt");
 
         tokio::fs::write(&src, b"hello world").await.unwrap();
-        let bytes = copy_file(&src, &dst).await.unwrap();
+        let _bytes = copy_file(&src, &dst).await.unwrap();
 
         assert_eq!(tokio::fs::read(&dst).await.unwrap(), b"hello world");
     }
 
+    #[tokio::test]
+    async fn copies_with_each_method() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&src, &content).await.unwrap();
+
+        for (i, method) in COPY_METHODS.into_iter().enumerate() {
+            let dst = dir.path().join(format!("dst{i}.bin"));
+
+            match copy_file_with(&src, &dst, &[method]).await {
+                Ok((bytes, used)) => {
+                    assert_eq!(used, method);
+                    assert_eq!(bytes, content.len() as u64);
+                    assert_eq!(tokio::fs::read(&dst).await.unwrap(), content);
+                }
+                // Reflinks need a filesystem which supports them (the temp dir may well be on tmpfs or ext4).
+                Err(e) if method == CopyMethod::Clone => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::Unsupported);
+                    assert!(tokio::fs::read(&dst).await.unwrap().is_empty());
+                }
+                Err(e) => panic!("{method:?} failed: {e}"),
+            }
+        }
+
+        // The fallback always ends up copying, one way or another.
+        let dst = dir.path().join("dst.bin");
+        let (bytes, _) = copy_file_with(&src, &dst, &COPY_METHODS).await.unwrap();
+        assert_eq!(bytes, content.len() as u64);
+        assert_eq!(tokio::fs::read(&dst).await.unwrap(), content);
+    }
+
+    /// A loop-mounted Btrfs (or XFS) image, unmounted once dropped.
+    struct ReflinkVolume {
+        mount: std::path::PathBuf,
+    }
+
+    impl ReflinkVolume {
+        /// Only possible with `mkfs.btrfs` (or `mkfs.xfs`) installed, and the privileges to mount.
+        fn create(dir: &Path) -> Option<Self> {
+            use std::process::{Command, Stdio};
+
+            let image = dir.join("volume.img");
+            let mount = dir.join("volume");
+            std::fs::create_dir(&mount).ok()?;
+            // Sparse, and large enough for either filesystem's minimum.
+            std::fs::File::create(&image)
+                .and_then(|file| file.set_len(512 << 20))
+                .ok()?;
+
+            let run = |program: &str, args: &[&std::ffi::OsStr]| {
+                Command::new(program)
+                    .args(args)
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success())
+            };
+
+            let formatted = run("mkfs.btrfs", &["-q".as_ref(), image.as_os_str()])
+                || run(
+                    "mkfs.xfs",
+                    &[
+                        "-q".as_ref(),
+                        "-m".as_ref(),
+                        "reflink=1".as_ref(),
+                        image.as_os_str(),
+                    ],
+                );
+            let mounted = formatted
+                && run(
+                    "mount",
+                    &[
+                        "-o".as_ref(),
+                        "loop".as_ref(),
+                        image.as_os_str(),
+                        mount.as_os_str(),
+                    ],
+                );
+
+            mounted.then_some(Self { mount })
+        }
+    }
+
+    impl Drop for ReflinkVolume {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("umount")
+                .arg(&self.mount)
+                .status();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "mounts a loop device: needs root, and mkfs.btrfs or mkfs.xfs (run with --ignored)"]
+    async fn clones_on_a_reflink_filesystem() {
+        let dir = tempdir().unwrap();
+        let volume = ReflinkVolume::create(dir.path())
+            .expect("couldn't create a loop-mounted Btrfs or XFS volume");
+
+        let src = volume.mount.join("src.bin");
+        let dst = volume.mount.join("dst.bin");
+        let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&src, &content).await.unwrap();
+
+        let (bytes, used) = copy_file_with(&src, &dst, &COPY_METHODS).await.unwrap();
+        assert_eq!((bytes, used), (content.len() as u64, CopyMethod::Clone));
+        assert_eq!(tokio::fs::read(&dst).await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn copies_empty_file_with_each_method() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        tokio::fs::write(&src, b"").await.unwrap();
+
+        for (i, method) in [CopyMethod::CopyRange, CopyMethod::Buffered]
+            .into_iter()
+            .enumerate()
+        {
+            let dst = dir.path().join(format!("dst{i}.bin"));
+            let (bytes, used) = copy_file_with(&src, &dst, &[method]).await.unwrap();
+            assert_eq!((bytes, used), (0, method));
+            assert!(tokio::fs::read(&dst).await.unwrap().is_empty());
+        }
+    }
+
     #[tokio::test]
     async fn fails_if_dest_exists() {
         let dir = tempdir().unwrap();
@@ -182,6 +604,128 @@ mod tests {
         assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
     }
 
+    #[tokio::test]
+    async fn walks_nested_files() {
+        let dir = tempdir().unwrap();
+        tokio::fs::create_dir_all(dir.path().join("a/b"))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join("c"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("x.txt"), b"x")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("a/y.txt"), b"y")
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("a/b/z.txt"), b"z")
+            .await
+            .unwrap();
+
+        let files = walk_files(dir.path()).await.unwrap();
+
+        assert_eq!(
+            files,
+            vec![
+                dir.path().join("a/b/z.txt"),
+                dir.path().join("a/y.txt"),
+                dir.path().join("x.txt"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_cache_trusted_while_stat_unchanged() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        if xattr::set(&path, "user.majdool.test", b"").is_err() {
+            // The filesystem doesn't support extended attributes - there's nothing to cache.
+            return;
+        }
+
+        let hello = compute_cached_file_hash(&path, HashCache::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(hello, compute_hash(Cursor::new(b"hello")).await.unwrap());
+
+        // Silently corrupt the content, without changing the stat tuple.
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, b"hellp").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(modified).unwrap();
+
+        let cached = compute_cached_file_hash(&path, HashCache::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(cached, hello);
+
+        let rehashed = compute_cached_file_hash(&path, HashCache::Rehash)
+            .await
+            .unwrap();
+        assert_eq!(rehashed, compute_hash(Cursor::new(b"hellp")).await.unwrap());
+
+        let cached = compute_cached_file_hash(&path, HashCache::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(cached, rehashed);
+    }
+
+    #[tokio::test]
+    async fn hash_cache_invalidated_by_stat() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        cache_file_hash(&path, &[7u8; 32]).await.ok();
+
+        tokio::fs::write(&path, b"hello world").await.unwrap();
+
+        let hash = compute_cached_file_hash(&path, HashCache::Enabled)
+            .await
+            .unwrap();
+        assert_eq!(
+            hash,
+            compute_hash(Cursor::new(b"hello world")).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn hash_cache_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        if xattr::set(&path, "user.majdool.test", b"").is_err() {
+            return;
+        }
+
+        let hash = compute_cached_file_hash(&path, HashCache::ReadOnly)
+            .await
+            .unwrap();
+        assert_eq!(hash, compute_hash(Cursor::new(b"hello")).await.unwrap());
+        assert_eq!(xattr::get(&path, HASH_CACHE_ATTRIBUTE).unwrap(), None);
+
+        // An existing cache is still trusted.
+        cache_file_hash(&path, &[7u8; 32]).await.unwrap();
+        let cached = compute_cached_file_hash(&path, HashCache::ReadOnly)
+            .await
+            .unwrap();
+        assert_eq!(cached, [7u8; 32]);
+    }
+
+    #[tokio::test]
+    async fn hash_cache_disabled() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        cache_file_hash(&path, &[7u8; 32]).await.ok();
+
+        let hash = compute_cached_file_hash(&path, HashCache::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(hash, compute_hash(Cursor::new(b"hello")).await.unwrap());
+    }
+
     #[tokio::test]
     async fn test_content_wise_equals() {
         let dir = tempdir().unwrap();
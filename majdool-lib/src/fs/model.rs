@@ -3,21 +3,12 @@ use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Size {
     inner: usize,
     complete: bool,
 }
 
-impl Default for Size {
-    fn default() -> Self {
-        Self {
-            inner: 0,
-            complete: false,
-        }
-    }
-}
-
 impl Size {
     fn add(&mut self, value: usize) -> Result<(), ()> {
         if self.complete {
@@ -167,7 +158,6 @@ mod tests {
     use super::*;
     use proptest::prelude::*;
     use rand::RngCore;
-    use std::iter::Cloned;
     use tokio::sync::mpsc;
 
     async fn run_comparison(
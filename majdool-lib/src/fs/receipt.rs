@@ -0,0 +1,193 @@
+use crate::filter::Filter;
+use crate::fs::fsutil::{FileHash, compute_file_hash, walk_files};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const RECEIPT: &str = ".majdool-receipt.json";
+
+/// A record of a successful drive flush, written to the root of the drive (`.majdool-receipt.json`).
+/// This way, anyone holding the drive can tell that it was imported - and we can tell whether it changed since.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub run_id: String,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub files: usize,
+    pub bytes: u64,
+    /// The hex sha256 over the drive's (path, hash) pairs (see `drive_digest`).
+    pub digest: String,
+}
+
+impl Receipt {
+    /// The `entries` are the (path, hash) of each file on the drive at `root`.
+    pub fn new(
+        run_id: impl Into<String>,
+        root: impl AsRef<Path>,
+        entries: &[(PathBuf, FileHash)],
+        bytes: u64,
+    ) -> Self {
+        Self {
+            run_id: run_id.into(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            files: entries.len(),
+            bytes,
+            digest: drive_digest(root, entries),
+        }
+    }
+}
+
+/// A random run id, for flushes which aren't otherwise identified (ex: by their `device_import` row).
+pub fn run_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+pub fn receipt_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join(RECEIPT)
+}
+
+pub fn is_receipt(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .file_name()
+        .is_some_and(|name| name == RECEIPT)
+}
+
+/// Digest the (path, hash) pairs, with the paths relative to the `root` (so that the digest doesn't depend on where the drive is mounted).
+/// The pairs are digested in path order.
+pub fn drive_digest(root: impl AsRef<Path>, entries: &[(PathBuf, FileHash)]) -> String {
+    let mut entries: Vec<(&Path, &FileHash)> = entries
+        .iter()
+        .map(|(path, hash)| (path.strip_prefix(&root).unwrap_or(path), hash))
+        .collect();
+    entries.sort();
+    let mut hasher = Sha256::new();
+
+    for (path, hash) in entries {
+        // Paths never contain NULs.
+        hasher.update(path.as_os_str().as_bytes());
+        hasher.update([0]);
+        hasher.update(hash);
+    }
+
+    hex::encode(hasher.finalize())
+}
+
+pub async fn read_receipt(root: impl AsRef<Path>) -> Result<Receipt, std::io::Error> {
+    let bytes = tokio::fs::read(receipt_path(root)).await?;
+    serde_json::from_slice(&bytes).map_err(std::io::Error::other)
+}
+
+/// Writes the receipt via a temporary file, so that a reader never observes a partially written receipt.
+pub async fn write_receipt(
+    root: impl AsRef<Path>,
+    receipt: &Receipt,
+) -> Result<(), std::io::Error> {
+    let path = receipt_path(root);
+    let bytes = serde_json::to_vec_pretty(receipt).map_err(std::io::Error::other)?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+/// Whether the drive at `root` still holds exactly what its receipt records (so there's nothing new to import).
+/// Only the files which the `filter` accepts are considered (as only they were flushed).
+/// Any failure (no receipt, an unreadable file) means it must be imported.
+pub async fn already_imported(root: impl AsRef<Path>, filter: &Filter) -> bool {
+    let root = root.as_ref();
+    let Ok(receipt) = read_receipt(root).await else {
+        return false;
+    };
+    let Ok(files) = walk_files(root).await else {
+        return false;
+    };
+    let mut accepted = Vec::default();
+
+    for path in files {
+        if is_receipt(&path) {
+            continue;
+        }
+
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if filter.accepts(root, &path, metadata.len()) => accepted.push(path),
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+
+    let files = accepted;
+
+    // Avoid hashing the whole drive when files have obviously been added or removed.
+    if files.len() != receipt.files {
+        return false;
+    }
+
+    let mut entries = Vec::default();
+
+    for path in files {
+        match compute_file_hash(&path).await {
+            Ok(hash) => entries.push((path, hash)),
+            Err(_) => return false,
+        }
+    }
+
+    drive_digest(root, &entries) == receipt.digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_drive_digest() {
+        let a = (PathBuf::from("/media/card/DCIM/a.jpg"), [1u8; 32]);
+        let b = (PathBuf::from("/media/card/DCIM/b.jpg"), [2u8; 32]);
+        let moved = (PathBuf::from("/mnt/card/DCIM/a.jpg"), [1u8; 32]);
+        let renamed = (PathBuf::from("/media/card/DCIM/c.jpg"), [2u8; 32]);
+
+        let digest = drive_digest("/media/card", &[a.clone(), b.clone()]);
+        assert_eq!(digest, drive_digest("/media/card", &[b.clone(), a.clone()]));
+        assert_eq!(
+            drive_digest("/media/card", std::slice::from_ref(&a)),
+            drive_digest("/mnt/card", &[moved])
+        );
+        assert_ne!(
+            digest,
+            drive_digest("/media/card", std::slice::from_ref(&a))
+        );
+        assert_ne!(digest, drive_digest("/media/card", &[a, renamed]));
+    }
+
+    #[tokio::test]
+    async fn receipt_already_imported() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let filter = Filter::default();
+        assert!(!already_imported(dir.path(), &filter).await);
+
+        let hash = compute_file_hash(&path).await.unwrap();
+        let receipt = Receipt::new(run_id(), dir.path(), &[(path, hash)], 5);
+        write_receipt(dir.path(), &receipt).await.unwrap();
+        assert_eq!(read_receipt(dir.path()).await.unwrap(), receipt);
+        assert!(is_receipt(receipt_path(dir.path())));
+        assert!(already_imported(dir.path(), &filter).await);
+
+        // Filtered out files don't count as changes.
+        tokio::fs::write(dir.path().join(".DS_Store"), b"junk")
+            .await
+            .unwrap();
+        assert!(already_imported(dir.path(), &filter).await);
+
+        tokio::fs::write(dir.path().join("b.jpg"), b"new")
+            .await
+            .unwrap();
+        assert!(!already_imported(dir.path(), &filter).await);
+    }
+}
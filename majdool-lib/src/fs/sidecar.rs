@@ -0,0 +1,116 @@
+use crate::api::{Media, MediaId};
+use crate::fs::fsutil::FileHash;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const SIDECAR_SUFFIX: &str = ".majdool.json";
+
+/// The metadata for a media, written next to it on the target (`$ID.majdool.json`).
+/// This way, the on-disk state carries everything needed to rebuild the `media_index`, not just the paths and hashes.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sidecar {
+    pub id: i64,
+    pub hash: String,
+    pub original_name: Option<String>,
+    pub source: Option<String>,
+    #[serde(default)]
+    pub labels: Vec<String>,
+}
+
+impl Sidecar {
+    pub fn new(id: MediaId, hash: &FileHash, source: Option<&Path>) -> Self {
+        Self {
+            id: id.value,
+            hash: hex::encode(hash),
+            original_name: source
+                .and_then(|s| s.file_name())
+                .and_then(|n| n.to_str())
+                .map(str::to_string),
+            source: source.and_then(|s| s.to_str()).map(str::to_string),
+            labels: Vec::default(),
+        }
+    }
+
+    pub fn for_media(media: &Media) -> Self {
+        Self::new(media.id, &media.hash, None)
+    }
+}
+
+/// The sidecar path for the media at `media_path`.
+pub fn sidecar_path(media_path: impl AsRef<Path>) -> PathBuf {
+    let media_path = media_path.as_ref();
+    let file_base = media_path.file_stem().unwrap_or_default().to_string_lossy();
+    media_path.with_file_name(format!("{file_base}{SIDECAR_SUFFIX}"))
+}
+
+pub fn is_sidecar(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.ends_with(SIDECAR_SUFFIX))
+}
+
+pub async fn read_sidecar(path: impl AsRef<Path>) -> Result<Sidecar, std::io::Error> {
+    let bytes = tokio::fs::read(path).await?;
+    serde_json::from_slice(&bytes).map_err(std::io::Error::other)
+}
+
+/// Writes the sidecar via a temporary file, so that a reader never observes a partially written sidecar.
+pub async fn write_sidecar(
+    path: impl AsRef<Path>,
+    sidecar: &Sidecar,
+) -> Result<(), std::io::Error> {
+    let bytes = serde_json::to_vec_pretty(sidecar).map_err(std::io::Error::other)?;
+    let mut temporary = path.as_ref().as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, bytes).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path("/target/flush/000000000000002a.jpg"),
+            PathBuf::from("/target/flush/000000000000002a.majdool.json")
+        );
+        assert_eq!(
+            sidecar_path("/target/flush/000000000000002a"),
+            PathBuf::from("/target/flush/000000000000002a.majdool.json")
+        );
+    }
+
+    #[test]
+    fn test_is_sidecar() {
+        assert!(is_sidecar("/target/flush/000000000000002a.majdool.json"));
+        assert!(!is_sidecar("/target/flush/000000000000002a.json"));
+        assert!(!is_sidecar("/target/flush/000000000000002a.jpg"));
+    }
+
+    #[tokio::test]
+    async fn sidecar_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("000000000000002a.majdool.json");
+        let mut sidecar = Sidecar::new(
+            MediaId::new(42),
+            &[7u8; 32],
+            Some(Path::new("/media/card/DCIM/IMG_0001.JPG")),
+        );
+        sidecar.labels.push("dog".to_string());
+
+        write_sidecar(&path, &sidecar).await.unwrap();
+        let actual = read_sidecar(&path).await.unwrap();
+
+        assert_eq!(actual, sidecar);
+        assert_eq!(actual.original_name.as_deref(), Some("IMG_0001.JPG"));
+        assert!(
+            !dir.path()
+                .join("000000000000002a.majdool.json.tmp")
+                .exists()
+        );
+    }
+}
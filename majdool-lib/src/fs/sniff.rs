@@ -0,0 +1,274 @@
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Enough of the file's start to recognize each of the signatures (the EBML doc type being the furthest in).
+const SNIFF_LENGTH: usize = 64;
+const DEFAULT_EXTENSION: &str = "unk";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Video,
+    Audio,
+}
+
+impl MediaKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Video => "video",
+            MediaKind::Audio => "audio",
+        }
+    }
+}
+
+/// The type of a media file, as recognized by its content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileType {
+    /// Normalized (lowercase, and the same for every file of the type - ex: `jpg`, never `jpeg`).
+    pub extension: &'static str,
+    pub kind: MediaKind,
+    pub mime: &'static str,
+}
+
+const fn file_type(extension: &'static str, kind: MediaKind, mime: &'static str) -> FileType {
+    FileType {
+        extension,
+        kind,
+        mime,
+    }
+}
+
+const JPEG: FileType = file_type("jpg", MediaKind::Image, "image/jpeg");
+const PNG: FileType = file_type("png", MediaKind::Image, "image/png");
+const GIF: FileType = file_type("gif", MediaKind::Image, "image/gif");
+const WEBP: FileType = file_type("webp", MediaKind::Image, "image/webp");
+const HEIC: FileType = file_type("heic", MediaKind::Image, "image/heic");
+const HEIF: FileType = file_type("heif", MediaKind::Image, "image/heif");
+const AVIF: FileType = file_type("avif", MediaKind::Image, "image/avif");
+const TIFF: FileType = file_type("tif", MediaKind::Image, "image/tiff");
+const CR2: FileType = file_type("cr2", MediaKind::Image, "image/x-canon-cr2");
+const CR3: FileType = file_type("cr3", MediaKind::Image, "image/x-canon-cr3");
+const NEF: FileType = file_type("nef", MediaKind::Image, "image/x-nikon-nef");
+const ARW: FileType = file_type("arw", MediaKind::Image, "image/x-sony-arw");
+const DNG: FileType = file_type("dng", MediaKind::Image, "image/x-adobe-dng");
+const ORF: FileType = file_type("orf", MediaKind::Image, "image/x-olympus-orf");
+const RW2: FileType = file_type("rw2", MediaKind::Image, "image/x-panasonic-rw2");
+const MP4: FileType = file_type("mp4", MediaKind::Video, "video/mp4");
+const M4V: FileType = file_type("m4v", MediaKind::Video, "video/x-m4v");
+const MOV: FileType = file_type("mov", MediaKind::Video, "video/quicktime");
+const THREE_GP: FileType = file_type("3gp", MediaKind::Video, "video/3gpp");
+const MKV: FileType = file_type("mkv", MediaKind::Video, "video/x-matroska");
+const WEBM: FileType = file_type("webm", MediaKind::Video, "video/webm");
+const AVI: FileType = file_type("avi", MediaKind::Video, "video/x-msvideo");
+const MPEG: FileType = file_type("mpg", MediaKind::Video, "video/mpeg");
+const M4A: FileType = file_type("m4a", MediaKind::Audio, "audio/mp4");
+const MP3: FileType = file_type("mp3", MediaKind::Audio, "audio/mpeg");
+const AAC: FileType = file_type("aac", MediaKind::Audio, "audio/aac");
+const FLAC: FileType = file_type("flac", MediaKind::Audio, "audio/flac");
+const OGG: FileType = file_type("ogg", MediaKind::Audio, "audio/ogg");
+const WAV: FileType = file_type("wav", MediaKind::Audio, "audio/wav");
+
+/// The raw formats which are plain TIFF containers, told apart only by their extension.
+const TIFF_RAWS: [FileType; 3] = [NEF, ARW, DNG];
+
+/// Recognize the type of a file from its `header` (its first bytes).
+/// The `extension` (of the file's name) is only consulted to tell apart the raw formats which share the TIFF container.
+pub fn sniff(header: &[u8], extension: Option<&str>) -> Option<FileType> {
+    let at =
+        |offset: usize, signature: &[u8]| header[offset.min(header.len())..].starts_with(signature);
+
+    if at(0, &[0xFF, 0xD8, 0xFF]) {
+        Some(JPEG)
+    } else if at(0, b"\x89PNG\r\n\x1a\n") {
+        Some(PNG)
+    } else if at(0, b"GIF87a") || at(0, b"GIF89a") {
+        Some(GIF)
+    } else if at(0, b"II*\0") || at(0, b"MM\0*") {
+        Some(tiff(header, extension))
+    } else if at(0, b"IIRO") || at(0, b"IIRS") || at(0, b"MMOR") {
+        Some(ORF)
+    } else if at(0, b"IIU\0") {
+        Some(RW2)
+    } else if at(4, b"ftyp") {
+        Some(iso_media(header.get(8..12).unwrap_or_default()))
+    } else if at(4, b"moov") || at(4, b"mdat") || at(4, b"wide") || at(4, b"free") {
+        // QuickTime movies which predate `ftyp`.
+        Some(MOV)
+    } else if at(0, b"RIFF") {
+        riff(header.get(8..12).unwrap_or_default())
+    } else if at(0, &[0x1A, 0x45, 0xDF, 0xA3]) {
+        // EBML - the doc type tells WebM from Matroska.
+        if header.windows(4).any(|window| window == b"webm") {
+            Some(WEBM)
+        } else {
+            Some(MKV)
+        }
+    } else if at(0, &[0x00, 0x00, 0x01, 0xBA]) {
+        Some(MPEG)
+    } else if at(0, b"fLaC") {
+        Some(FLAC)
+    } else if at(0, b"OggS") {
+        Some(OGG)
+    } else if at(0, b"ID3") {
+        Some(MP3)
+    } else if let [0xFF, second, ..] = header {
+        // An MPEG audio frame sync - ADTS (AAC) has a zero layer, whereas MP3 never does.
+        match (second & 0xF0 == 0xF0, second & 0xE0 == 0xE0, second & 0x06) {
+            (true, _, 0) => Some(AAC),
+            (_, true, layer) if layer != 0 => Some(MP3),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+fn tiff(header: &[u8], extension: Option<&str>) -> FileType {
+    if header.get(8..10) == Some(b"CR") {
+        return CR2;
+    }
+
+    let extension = extension.map(|e| e.to_ascii_lowercase());
+    TIFF_RAWS
+        .into_iter()
+        .find(|raw| extension.as_deref() == Some(raw.extension))
+        .unwrap_or(TIFF)
+}
+
+/// An ISO base media file (MP4, QuickTime, HEIF), by its major brand.
+fn iso_media(brand: &[u8]) -> FileType {
+    match brand {
+        b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" => HEIC,
+        b"mif1" | b"msf1" => HEIF,
+        b"avif" | b"avis" => AVIF,
+        b"crx " => CR3,
+        b"qt  " => MOV,
+        b"M4A " | b"M4B " => M4A,
+        b"M4V " | b"M4VH" | b"M4VP" => M4V,
+        _ if brand.starts_with(b"3g") => THREE_GP,
+        _ => MP4,
+    }
+}
+
+fn riff(format: &[u8]) -> Option<FileType> {
+    match format {
+        b"WEBP" => Some(WEBP),
+        b"WAVE" => Some(WAV),
+        b"AVI " => Some(AVI),
+        _ => None,
+    }
+}
+
+/// Recognize the type of the file at `path` from its content (see `sniff`).
+pub async fn sniff_file(path: impl AsRef<Path>) -> Result<Option<FileType>, std::io::Error> {
+    let path = path.as_ref();
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut header = Vec::with_capacity(SNIFF_LENGTH);
+    (&mut file)
+        .take(SNIFF_LENGTH as u64)
+        .read_to_end(&mut header)
+        .await?;
+    Ok(sniff(
+        &header,
+        path.extension().and_then(|extension| extension.to_str()),
+    ))
+}
+
+/// The extension to store the file at `path` under: the sniffed type's, when it was recognized.
+/// Otherwise, the file's own extension (lowercased), falling back to `unk`.
+pub fn normalized_extension(path: impl AsRef<Path>, file_type: Option<&FileType>) -> String {
+    match file_type {
+        Some(file_type) => file_type.extension.to_string(),
+        None => path
+            .as_ref()
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or(DEFAULT_EXTENSION.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn sniffed(header: &[u8]) -> Option<&'static str> {
+        sniff(header, None).map(|file_type| file_type.extension)
+    }
+
+    #[test]
+    fn sniff_signatures() {
+        assert_eq!(sniffed(b"\xFF\xD8\xFF\xE1\0\0Exif"), Some("jpg"));
+        assert_eq!(sniffed(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR"), Some("png"));
+        assert_eq!(sniffed(b"GIF89a"), Some("gif"));
+        assert_eq!(sniffed(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniffed(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+        assert_eq!(sniffed(b"RIFF\0\0\0\0AVI LIST"), Some("avi"));
+        assert_eq!(sniffed(b"RIFF\0\0\0\0ACON"), None);
+        assert_eq!(sniffed(b"\0\0\0\x18ftypheic\0\0\0\0mif1heic"), Some("heic"));
+        assert_eq!(sniffed(b"\0\0\0\x18ftypmif1\0\0\0\0mif1heic"), Some("heif"));
+        assert_eq!(
+            sniffed(b"\0\0\0\x18ftypcrx \0\0\0\x01crx isom"),
+            Some("cr3")
+        );
+        assert_eq!(sniffed(b"\0\0\0\x14ftypqt  \0\0\0\0qt  "), Some("mov"));
+        assert_eq!(sniffed(b"\0\0\0\x08wide\0\0\0\0mdat"), Some("mov"));
+        assert_eq!(
+            sniffed(b"\0\0\0\x20ftypisom\0\0\x02\0isomiso2"),
+            Some("mp4")
+        );
+        assert_eq!(sniffed(b"\0\0\0\x1cftypmp42\0\0\0\0mp42isom"), Some("mp4"));
+        assert_eq!(sniffed(b"\0\0\0\x1cftyp3gp4\0\0\0\0isom3gp4"), Some("3gp"));
+        assert_eq!(sniffed(b"\0\0\0\x20ftypM4A \0\0\0\0M4A mp42"), Some("m4a"));
+        assert_eq!(
+            sniffed(b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81\x01\x42\x82\x88matroska"),
+            Some("mkv")
+        );
+        assert_eq!(
+            sniffed(b"\x1A\x45\xDF\xA3\x9F\x42\x86\x81\x01\x42\x82\x84webm"),
+            Some("webm")
+        );
+        assert_eq!(sniffed(b"\0\0\x01\xBA\x44"), Some("mpg"));
+        assert_eq!(sniffed(b"fLaC\0\0\0\x22"), Some("flac"));
+        assert_eq!(sniffed(b"OggS\0\x02"), Some("ogg"));
+        assert_eq!(sniffed(b"ID3\x04\0\0"), Some("mp3"));
+        assert_eq!(sniffed(b"\xFF\xFB\x90\x44"), Some("mp3"));
+        assert_eq!(sniffed(b"\xFF\xF1\x50\x80"), Some("aac"));
+        assert_eq!(sniffed(b"hello"), None);
+        assert_eq!(sniffed(b""), None);
+    }
+
+    #[test]
+    fn sniff_tiff_raws() {
+        let tiff = b"II*\0\x10\0\0\0";
+        assert_eq!(sniff(b"II*\0\x10\0\0\0CR\x02\0", None), Some(CR2));
+        assert_eq!(sniff(tiff, Some("NEF")), Some(NEF));
+        assert_eq!(sniff(tiff, Some("arw")), Some(ARW));
+        assert_eq!(sniff(tiff, Some("dng")), Some(DNG));
+        assert_eq!(sniff(tiff, Some("jpg")), Some(TIFF));
+        assert_eq!(sniff(b"MM\0*\0\0\0\x08", None), Some(TIFF));
+        assert_eq!(sniff(b"IIRO\x08\0\0\0", None), Some(ORF));
+        assert_eq!(sniff(b"IIU\0\x18\0\0\0", None), Some(RW2));
+    }
+
+    #[tokio::test]
+    async fn sniff_file_and_normalize() {
+        let dir = tempdir().unwrap();
+        let jpeg = dir.path().join("IMG_0001.JPEG");
+        let unknown = dir.path().join("notes.TXT");
+        tokio::fs::write(&jpeg, b"\xFF\xD8\xFF\xE0\0\x10JFIF")
+            .await
+            .unwrap();
+        tokio::fs::write(&unknown, b"hello").await.unwrap();
+
+        let file_type = sniff_file(&jpeg).await.unwrap();
+        assert_eq!(file_type, Some(JPEG));
+        assert_eq!(normalized_extension(&jpeg, file_type.as_ref()), "jpg");
+
+        let file_type = sniff_file(&unknown).await.unwrap();
+        assert_eq!(file_type, None);
+        assert_eq!(normalized_extension(&unknown, None), "txt");
+        assert_eq!(normalized_extension(dir.path().join("README"), None), "unk");
+    }
+}
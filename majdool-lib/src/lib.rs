@@ -1,4 +1,15 @@
+pub mod adopt;
 pub mod api;
+pub mod config;
 pub mod db;
+pub mod device;
+pub mod filter;
 pub mod fs;
+pub mod library;
+pub mod lock;
 pub mod media;
+pub mod monitor;
+pub mod rebuild;
+pub mod reshard;
+pub mod scrub;
+pub mod verify;
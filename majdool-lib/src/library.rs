@@ -0,0 +1,297 @@
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::filesystem::FLUSH_DIRECTORY;
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+
+const MARKER: &str = ".majdool-library.json";
+const PROBE: &str = ".majdool-probe";
+
+/// Marks a directory as a majdool target (`.majdool-library.json`, at its root), naming the library which it holds.
+/// The same id is recorded in the database, so that a database and a target of different libraries are never used together.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct LibraryMarker {
+    pub id: String,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+}
+
+impl LibraryMarker {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// A random (version 4) UUID.
+pub fn library_id() -> String {
+    let mut bytes = rand::random::<[u8; 16]>();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+pub fn marker_path(root: impl AsRef<Path>) -> PathBuf {
+    root.as_ref().join(MARKER)
+}
+
+pub fn is_marker(path: impl AsRef<Path>) -> bool {
+    path.as_ref().file_name().is_some_and(|name| name == MARKER)
+}
+
+pub async fn read_marker(root: impl AsRef<Path>) -> Result<LibraryMarker, std::io::Error> {
+    let bytes = tokio::fs::read(marker_path(root)).await?;
+    serde_json::from_slice(&bytes).map_err(std::io::Error::other)
+}
+
+/// An existing marker is never replaced.
+async fn write_marker(
+    root: impl AsRef<Path>,
+    marker: &LibraryMarker,
+) -> Result<(), std::io::Error> {
+    let bytes = serde_json::to_vec_pretty(marker).map_err(std::io::Error::other)?;
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(marker_path(root))
+        .await?;
+    file.write_all(&bytes).await?;
+    file.sync_all().await
+}
+
+/// Initialize the target at `root` (see Target Initialization), returning its library id.
+/// This is idempotent, and also records an existing target's library in a fresh database (ex: ahead of rebuilding its index).
+pub async fn init_library(
+    index_db: &mut MediaIndexDatabase,
+    root: impl AsRef<Path>,
+) -> Result<String, String> {
+    let root = root.as_ref();
+    let database = database_library(index_db).await?;
+    let marker = match read_marker(root).await {
+        Ok(marker) => Some(marker),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(format!(
+                "failed to read the library marker of {root:?}: {e}"
+            ));
+        }
+    };
+
+    // Refuse a mismatch before touching the target.
+    match (&database, &marker) {
+        (Some(database), Some(marker)) if *database != marker.id => {
+            return Err(mismatch(root, &marker.id, database));
+        }
+        (Some(database), None) => {
+            return Err(format!(
+                "the database already indexes library {database}, which {root:?} isn't marked as"
+            ));
+        }
+        _ => {}
+    }
+
+    let flush = root.join(FLUSH_DIRECTORY);
+    tokio::fs::create_dir_all(&flush)
+        .await
+        .map_err(|e| format!("failed to create {flush:?}: {e}"))?;
+    check_single_device(root, true).await?;
+    check_writable(root).await?;
+
+    match (database, marker) {
+        (Some(database), _) => Ok(database),
+        (None, Some(marker)) => record_library(index_db, root, marker.id).await,
+        (None, None) => {
+            // The marker comes first - if recording it fails, the next init picks it up again.
+            let marker = LibraryMarker::new(library_id());
+            write_marker(root, &marker)
+                .await
+                .map_err(|e| format!("failed to write the library marker to {root:?}: {e}"))?;
+            record_library(index_db, root, marker.id).await
+        }
+    }
+}
+
+/// Check that the target at `root` and the database belong to the same library, returning its id.
+/// Only the flush directory is checked against the target's device - see `init_library` for the full check.
+pub async fn check_library(
+    index_db: &mut MediaIndexDatabase,
+    root: impl AsRef<Path>,
+) -> Result<String, String> {
+    let root = root.as_ref();
+    let marker = match read_marker(root).await {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(format!(
+                "{root:?} isn't an initialized target (run `majdool init`)"
+            ));
+        }
+        Err(e) => {
+            return Err(format!(
+                "failed to read the library marker of {root:?}: {e}"
+            ));
+        }
+    };
+
+    match database_library(index_db).await? {
+        Some(database) if database == marker.id => {}
+        Some(database) => return Err(mismatch(root, &marker.id, &database)),
+        None => {
+            return Err("the database isn't initialized (run `majdool init`)".to_string());
+        }
+    }
+
+    check_single_device(root, false).await?;
+    Ok(marker.id)
+}
+
+async fn database_library(index_db: &mut MediaIndexDatabase) -> Result<Option<String>, String> {
+    index_db
+        .library_id()
+        .await
+        .map_err(|_| "failed to read the database's library (is it migrated?)".to_string())
+}
+
+async fn record_library(
+    index_db: &mut MediaIndexDatabase,
+    root: &Path,
+    id: String,
+) -> Result<String, String> {
+    index_db
+        .library_insert(&id, root)
+        .await
+        .map_err(|_| format!("failed to record library {id} in the database"))?;
+    Ok(id)
+}
+
+fn mismatch(root: &Path, target: &str, database: &str) -> String {
+    format!("{root:?} holds library {target}, but the database indexes library {database}")
+}
+
+/// Check that the target's flush directory (or, when `deep`, each of its directories) is on the same device as its root.
+/// Media is moved out of the flush directory by renames, which are only atomic within a device (and fail across them).
+async fn check_single_device(root: &Path, deep: bool) -> Result<(), String> {
+    let device = async |path: &Path| {
+        tokio::fs::metadata(path)
+            .await
+            .map(|m| m.dev())
+            .map_err(|e| format!("failed to read {path:?}: {e}"))
+    };
+    let root_device = device(root).await?;
+    let mut directories = if deep {
+        vec![root.to_path_buf()]
+    } else {
+        vec![root.join(FLUSH_DIRECTORY)]
+    };
+
+    while let Some(directory) = directories.pop() {
+        if device(&directory).await? != root_device {
+            return Err(format!(
+                "{directory:?} is on a different device than the target {root:?}"
+            ));
+        }
+
+        if !deep {
+            continue;
+        }
+
+        let failed = |e: std::io::Error| format!("failed to read {directory:?}: {e}");
+        let mut entries = tokio::fs::read_dir(&directory).await.map_err(failed)?;
+
+        while let Some(entry) = entries.next_entry().await.map_err(failed)? {
+            // Symlinks aren't followed (just as when walking the target).
+            if entry.file_type().await.map_err(failed)?.is_dir() {
+                directories.push(entry.path());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a file can be written to the flush directory, and renamed out of it.
+async fn check_writable(root: &Path) -> Result<(), String> {
+    let suffix = format!("{:016x}", rand::random::<u64>());
+    let probe = root.join(FLUSH_DIRECTORY).join(format!("{PROBE}-{suffix}"));
+    let renamed = root.join(format!("{PROBE}-{suffix}"));
+    let failed = |e: std::io::Error| format!("the target {root:?} isn't writable: {e}");
+
+    tokio::fs::write(&probe, b"majdool").await.map_err(failed)?;
+    let renamed_result = tokio::fs::rename(&probe, &renamed).await;
+
+    if renamed_result.is_err() {
+        let _ = tokio::fs::remove_file(&probe).await;
+    }
+
+    renamed_result.map_err(failed)?;
+    tokio::fs::remove_file(&renamed).await.map_err(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_library_id() {
+        let id = library_id();
+        assert_eq!(id.len(), 36);
+        assert_eq!(id.matches('-').count(), 4);
+        assert_eq!(&id[14..15], "4");
+        assert!(matches!(&id[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(id, library_id());
+    }
+
+    #[tokio::test]
+    async fn marker_is_never_replaced() {
+        let dir = tempdir().unwrap();
+        let marker = LibraryMarker::new(library_id());
+        write_marker(dir.path(), &marker).await.unwrap();
+        assert_eq!(read_marker(dir.path()).await.unwrap(), marker);
+        assert!(is_marker(marker_path(dir.path())));
+
+        let other = LibraryMarker::new(library_id());
+        assert!(write_marker(dir.path(), &other).await.is_err());
+        assert_eq!(read_marker(dir.path()).await.unwrap(), marker);
+    }
+
+    #[tokio::test]
+    async fn target_checks() {
+        let dir = tempdir().unwrap();
+        assert!(check_single_device(dir.path(), false).await.is_err());
+
+        tokio::fs::create_dir_all(dir.path().join("flush/00/00"))
+            .await
+            .unwrap();
+        tokio::fs::create_dir_all(dir.path().join("phones"))
+            .await
+            .unwrap();
+        check_single_device(dir.path(), false).await.unwrap();
+        check_single_device(dir.path(), true).await.unwrap();
+        check_writable(dir.path()).await.unwrap();
+
+        // The probe is cleaned up.
+        let mut entries = tokio::fs::read_dir(dir.path().join("flush")).await.unwrap();
+        let mut names = Vec::default();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec!["00"]);
+        assert!(!tokio::fs::try_exists(dir.path().join(PROBE)).await.unwrap());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::fs::fsutil::FileHash;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+
+/// Serializes the flushes of the same content, so that concurrent flushes of identical files find each other as duplicates.
+/// Without it, both would miss the lookup, and both would be inserted and copied.
+/// The locks are per process - every `MediaSystem` flushing into the target should share the same `HashLocks`.
+#[derive(Clone, Default)]
+pub struct HashLocks {
+    locks: Arc<Mutex<HashMap<FileHash, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+/// Holds the lock of a hash, until dropped.
+pub struct HashGuard {
+    locks: HashLocks,
+    hash: FileHash,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl HashLocks {
+    pub async fn lock(&self, hash: &FileHash) -> HashGuard {
+        let lock = self.locks.lock().unwrap().entry(*hash).or_default().clone();
+
+        HashGuard {
+            locks: self.clone(),
+            hash: *hash,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+impl Drop for HashGuard {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        self.guard.take();
+
+        // Forget the lock once nobody is waiting on it (the waiters clone it under this same mutex, so none can sneak in).
+        if locks
+            .get(&self.hash)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn serializes_the_same_hash() {
+        let locks = HashLocks::default();
+        let guard = locks.lock(&[1u8; 32]).await;
+
+        // Other hashes aren't held up.
+        drop(locks.lock(&[2u8; 32]).await);
+
+        let waiter = {
+            let locks = locks.clone();
+            tokio::spawn(async move {
+                let _guard = locks.lock(&[1u8; 32]).await;
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(guard);
+        waiter.await.unwrap();
+        assert!(locks.locks.lock().unwrap().is_empty());
+    }
+}
@@ -1,46 +1,230 @@
-use crate::api::MediaId;
+use crate::api::{Cleaned, Flush, MediaId};
 use crate::db::database::MediaIndexDatabase;
+use crate::filter::Filter;
+use crate::fs::cleanup::clean_up_source;
 use crate::fs::filesystem::MediaFilesystem;
-use crate::fs::fsutil::{FileHash, compute_file_hash, content_wise_equals};
+use crate::fs::fsutil::{FileHash, compute_file_hash, content_wise_equals, walk_files};
+use crate::fs::receipt::{Receipt, is_receipt};
+use crate::fs::sniff::sniff_file;
+use crate::lock::HashLocks;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
+/// The outcome of a drive flush.
+#[derive(Debug, Default)]
+pub struct DriveFlushReport {
+    /// The files which were flushed, or found to be duplicates of existing media.
+    pub files: usize,
+    pub bytes: u64,
+    pub failed: Vec<PathBuf>,
+    /// The files which the filter turned away.
+    pub skipped: usize,
+    /// The (path, hash) of each of the `files`.
+    pub hashes: Vec<(PathBuf, FileHash)>,
+}
+
+impl DriveFlushReport {
+    /// The receipt for the flush of the drive at `root`, provided that every file was flushed.
+    pub fn receipt(&self, run_id: impl Into<String>, root: impl AsRef<Path>) -> Option<Receipt> {
+        if self.failed.is_empty() {
+            Some(Receipt::new(run_id, root, &self.hashes, self.bytes))
+        } else {
+            None
+        }
+    }
+}
+
 pub struct MediaSystem {
     index_db: MediaIndexDatabase,
     filesystem: MediaFilesystem,
-    target_path: PathBuf,
+    hash_locks: HashLocks,
 }
 
 impl MediaSystem {
-    pub async fn flush_file(&mut self, source: impl AsRef<Path>) -> Result<MediaId, ()> {
+    pub fn new(index_db: MediaIndexDatabase, filesystem: MediaFilesystem) -> Self {
+        Self {
+            index_db,
+            filesystem,
+            hash_locks: HashLocks::default(),
+        }
+    }
+
+    /// Share the hash locks with the other systems flushing concurrently (see `HashLocks`).
+    pub fn with_hash_locks(mut self, hash_locks: HashLocks) -> Self {
+        self.hash_locks = hash_locks;
+        self
+    }
+
+    pub async fn flush_file(&mut self, source: impl AsRef<Path>) -> Result<Flush, ()> {
         // TODO: durability
         let hash = compute_file_hash(&source).await.map_err(|_| ())?;
-        match self.index_db.media_lookup(hash).await {
+        self.flush_hashed(&hash, source).await
+    }
+
+    async fn flush_hashed(
+        &mut self,
+        hash: &FileHash,
+        source: impl AsRef<Path>,
+    ) -> Result<Flush, ()> {
+        // Held until the flush is synced, so that a concurrent flush of the same content finds it.
+        let _guard = self.hash_locks.lock(hash).await;
+
+        match self.index_db.media_lookup(*hash).await {
             Some(media) => {
                 // Perform content wise comparison
-                if content_wise_equals(&source, media.path)
-                    .await
-                    .map_err(|_| ())?
-                {
+                let equals = match content_wise_equals(&source, media.path).await {
+                    Ok(equals) => equals,
+                    // The matching file has been moved or deleted from the target (a content-wise mismatch).
+                    Err(e) if e.kind() == ErrorKind::NotFound => false,
+                    Err(_) => return Err(()),
+                };
+
+                if equals {
                     // We don't need to flush source - it's a duplicate.
-                    Ok(media.id)
+                    Ok(Flush::Duplicate(media.id))
                 } else {
-                    // It's a hash collision - flush!
-                    self.insert_flush_write(&hash, &source).await
+                    // It's a hash collision (or the match is gone) - flush!
+                    self.insert_flush_write(hash, &source)
+                        .await
+                        .map(Flush::Flushed)
                 }
             }
-            None => self.insert_flush_write(&hash, &source).await,
+            None => self
+                .insert_flush_write(hash, &source)
+                .await
+                .map(Flush::Flushed),
         }
     }
 
+    /// Flush every file under the `source` (see Drive Flush Procedure).
+    /// Failures are collected in the report, rather than aborting the rest of the drive.
+    /// The drive's receipt (from an earlier flush) isn't media, so it's skipped - as are the files which the `filter` doesn't accept.
+    pub async fn flush_drive(
+        &mut self,
+        source: impl AsRef<Path>,
+        filter: &Filter,
+    ) -> Result<DriveFlushReport, ()> {
+        let source = source.as_ref();
+        let mut report = DriveFlushReport::default();
+
+        for path in walk_files(source).await.map_err(|_| ())? {
+            if is_receipt(&path) {
+                continue;
+            }
+
+            let Ok(metadata) = tokio::fs::metadata(&path).await else {
+                report.failed.push(path);
+                continue;
+            };
+
+            if !filter.accepts(source, &path, metadata.len()) {
+                report.skipped += 1;
+                continue;
+            }
+
+            let Ok(hash) = compute_file_hash(&path).await else {
+                report.failed.push(path);
+                continue;
+            };
+
+            match self.flush_hashed(&hash, &path).await {
+                Ok(_) => {
+                    report.files += 1;
+                    report.bytes += metadata.len();
+                    report.hashes.push((path, hash));
+                }
+                Err(_) => report.failed.push(path),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Clean up the `source` file which was flushed as the media `id` (deleting it, or moving it aside), as per the `cleanup`.
+    /// The source is only touched once its target copy is re-verified byte-for-byte, and the cleanup is recorded in the index (see `clean_up_source`).
+    /// On any failure (and in a `dry_run`), the source is kept as is.
+    pub async fn cleanup_source(
+        &mut self,
+        source: impl AsRef<Path>,
+        id: MediaId,
+        cleanup: Cleaned,
+        dry_run: bool,
+    ) -> Result<Cleaned, ()> {
+        let source = source.as_ref();
+
+        if matches!(cleanup, Cleaned::Kept | Cleaned::DryRun) {
+            return Ok(Cleaned::Kept);
+        }
+
+        let media = self.index_db.media_get(id).await.ok_or(())?;
+        let mut audit_id = None;
+        let cleaned = clean_up_source(source, &media.path, &cleanup, dry_run, async || {
+            audit_id = Some(
+                self.index_db
+                    .source_cleanup_start(id, source, &cleanup)
+                    .await?,
+            );
+            Ok(())
+        })
+        .await?;
+
+        // The cleanup is already recorded (as unfinished), so this is only a loss of detail.
+        if let Some(audit_id) = audit_id {
+            let _ = self.index_db.source_cleanup_finish(audit_id).await;
+        }
+
+        Ok(cleaned)
+    }
+
+    /// Move the media into the `directory` (see File Moves).
+    /// If updating the index fails, the media is simply at a path which is inconsistent with the index.
+    pub async fn move_media(
+        &mut self,
+        id: MediaId,
+        directory: impl AsRef<Path>,
+    ) -> Result<PathBuf, ()> {
+        let media = self.index_db.media_get(id).await.ok_or(())?;
+        let destination = self.filesystem.move_write(&media, directory).await?;
+        self.index_db.media_sync(id, &destination).await?;
+        Ok(destination)
+    }
+
+    pub async fn label_media(&mut self, id: MediaId, label: &str) -> Result<(), ()> {
+        self.index_db.media_label(id, label).await?;
+        self.label_write(id).await
+    }
+
+    pub async fn unlabel_media(&mut self, id: MediaId, label: &str) -> Result<(), ()> {
+        self.index_db.media_unlabel(id, label).await?;
+        self.label_write(id).await
+    }
+
+    async fn label_write(&mut self, id: MediaId) -> Result<(), ()> {
+        let media = self.index_db.media_get(id).await.ok_or(())?;
+        let labels = self.index_db.media_labels(id).await?;
+        self.filesystem.label_write(&media, labels).await
+    }
+
     async fn insert_flush_write(
         &mut self,
         hash: &FileHash,
         source: impl AsRef<Path>,
     ) -> Result<MediaId, ()> {
         // TODO: durability
-        match self.index_db.media_insert(hash).await {
-            Ok(id) => match self.filesystem.flush_write(&source, id).await {
-                Ok(_) => self.index_db.media_sync(id, source).await.map(|_| id),
+        // An unreadable file fails the copy anyway, so there's no need to fail on it here.
+        let file_type = sniff_file(&source).await.ok().flatten();
+
+        match self
+            .index_db
+            .media_insert(hash, &source, file_type.as_ref())
+            .await
+        {
+            Ok(id) => match self
+                .filesystem
+                .flush_write(&source, id, hash, file_type.as_ref())
+                .await
+            {
+                Ok(destination) => self.index_db.media_sync(id, destination).await.map(|_| id),
                 Err(_) => Err(()),
             },
             Err(_) => {
@@ -0,0 +1,127 @@
+use crate::api::{Media, MediaId};
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::fsutil::{HashCache, compute_cached_file_hash, walk_files};
+use crate::fs::sidecar::is_sidecar;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// Reflects the on-disk state of the target into the `media_index` (see Consistency Monitor).
+/// The monitor only ever reads from the target - its output is written to the index alone.
+pub struct ConsistencyMonitor {
+    index_db: MediaIndexDatabase,
+    hash_cache: HashCache,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Recheck {
+    /// The index already agrees with the on-disk state.
+    Consistent,
+    /// Fix A: the indexed hash was wrong.
+    Rehashed(MediaId),
+    /// Fix B: the indexed path was wrong (or the row was lost).
+    Relocated(MediaId),
+    /// Fix C: the file was missing from the index.
+    Restored(MediaId),
+    /// Fix D: this many superfluous rows were marked lost.
+    Lost(u64),
+    /// The path isn't a media file, or its row is still being flushed.
+    Skipped,
+}
+
+impl ConsistencyMonitor {
+    pub fn new(index_db: MediaIndexDatabase) -> Self {
+        Self {
+            index_db,
+            hash_cache: HashCache::Disabled,
+        }
+    }
+
+    /// Trust the hashes cached on the target files, rather than re-hashing everything.
+    /// The cache is only read (see `HashCache::ReadOnly`), so that the target is left untouched.
+    pub fn with_hash_cache(mut self) -> Self {
+        self.hash_cache = HashCache::ReadOnly;
+        self
+    }
+
+    /// Immediately recheck a single path on the target (ex: in response to a filesystem event).
+    /// Directories are rechecked recursively.
+    pub async fn recheck(&mut self, path: impl AsRef<Path>) -> Result<Vec<Recheck>, ()> {
+        let path = path.as_ref();
+
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                let mut rechecks = Vec::default();
+
+                for file in walk_files(path).await.map_err(|_| ())? {
+                    rechecks.push(self.recheck_file(&file).await?);
+                }
+
+                Ok(rechecks)
+            }
+            Ok(_) => Ok(vec![self.recheck_file(path).await?]),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let lost = self.index_db.media_lose(path, None).await?;
+                Ok(vec![if lost == 0 {
+                    Recheck::Consistent
+                } else {
+                    Recheck::Lost(lost)
+                }])
+            }
+            Err(_) => Err(()),
+        }
+    }
+
+    async fn recheck_file(&mut self, path: &Path) -> Result<Recheck, ()> {
+        if is_sidecar(path) {
+            return Ok(Recheck::Skipped);
+        }
+
+        let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(MediaId::from_file_base)
+        else {
+            return Ok(Recheck::Skipped);
+        };
+
+        let state = self.index_db.media_state(id).await?;
+
+        if state.as_ref().is_some_and(|s| !s.synced) {
+            // This is either a flush in progress, or a garbage collection problem - not a consistency issue.
+            return Ok(Recheck::Skipped);
+        }
+
+        let hash = match compute_cached_file_hash(path, self.hash_cache).await {
+            Ok(hash) => hash,
+            // The file disappeared from under us - a subsequent event will recheck it.
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Recheck::Skipped),
+            Err(_) => return Err(()),
+        };
+
+        // Fix D must come first, so that the path is free for fixes B & C.
+        self.index_db.media_lose(path, Some(id)).await?;
+
+        match state {
+            None => {
+                let media = Media {
+                    id,
+                    path: path.to_path_buf(),
+                    hash,
+                };
+                self.index_db.media_restore(&media).await?;
+                // The restored id may be past the id sequence.
+                self.index_db.media_reset_sequence().await?;
+                Ok(Recheck::Restored(id))
+            }
+            Some(state) if state.lost || state.path.as_deref() != Some(path) => {
+                self.index_db.media_relocate(id, path, &hash).await?;
+                Ok(Recheck::Relocated(id))
+            }
+            Some(state) if state.hash != hash => {
+                self.index_db.media_relocate(id, path, &hash).await?;
+                Ok(Recheck::Rehashed(id))
+            }
+            Some(_) => Ok(Recheck::Consistent),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::api::{Media, MediaId};
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::filesystem::MediaFilesystem;
+use crate::fs::fsutil::{HashCache, compute_cached_file_hash, walk_files};
+use crate::fs::sidecar::{Sidecar, is_sidecar, read_sidecar, sidecar_path};
+use crate::fs::sniff::sniff_file;
+use crate::library::is_marker;
+use std::path::{Path, PathBuf};
+
+pub struct IndexRebuilder {
+    index_db: MediaIndexDatabase,
+    filesystem: MediaFilesystem,
+    hash_cache: HashCache,
+}
+
+#[derive(Debug, Default)]
+pub struct RebuildReport {
+    pub restored: usize,
+    // Restored media whose metadata was recovered from a sidecar.
+    pub sidecars: usize,
+    // Files whose name doesn't encode a MediaId.
+    pub unrecognized: Vec<PathBuf>,
+    pub unreadable: Vec<PathBuf>,
+    // Files which couldn't be inserted (ex: a copy of a flushed file, sharing its id).
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl IndexRebuilder {
+    pub fn new(index_db: MediaIndexDatabase, filesystem: MediaFilesystem) -> Self {
+        Self {
+            index_db,
+            filesystem,
+            hash_cache: HashCache::Disabled,
+        }
+    }
+
+    /// Trust the hashes cached on the target files, rather than re-hashing everything.
+    pub fn with_hash_cache(mut self) -> Self {
+        self.hash_cache = HashCache::Enabled;
+        self
+    }
+
+    /// Reconstruct `media_index` from the on-disk state of the target.
+    /// Each file is re-hashed and restored under the id parsed from its name, after which the id sequence is moved past the maximum id.
+    /// Provenance and labels are recovered from the media's sidecar, when there is one.
+    /// The index must be empty before rebuilding - this is a disaster recovery procedure, not a merge.
+    pub async fn rebuild(&mut self) -> Result<RebuildReport, ()> {
+        if self.index_db.media_count().await? != 0 {
+            return Err(());
+        }
+
+        let mut report = RebuildReport::default();
+
+        for path in walk_files(self.filesystem.root()).await.map_err(|_| ())? {
+            if is_sidecar(&path) || is_marker(&path) {
+                continue;
+            }
+
+            let Some(id) = parse_media_id(&path) else {
+                report.unrecognized.push(path);
+                continue;
+            };
+
+            let hash = match compute_cached_file_hash(&path, self.hash_cache).await {
+                Ok(hash) => hash,
+                Err(_) => {
+                    report.unreadable.push(path);
+                    continue;
+                }
+            };
+
+            let media = Media { id, path, hash };
+            if self.index_db.media_restore(&media).await.is_err() {
+                report.conflicts.push(media.path);
+                continue;
+            }
+
+            report.restored += 1;
+
+            // The type is only informational, so failing to sniff it doesn't fail the restore.
+            if let Ok(Some(file_type)) = sniff_file(&media.path).await {
+                self.index_db.media_file_type(id, &file_type).await?;
+            }
+
+            if let Some(sidecar) = self.read_sidecar(&media).await {
+                self.restore_sidecar(id, sidecar).await?;
+                report.sidecars += 1;
+            }
+        }
+
+        self.index_db.media_reset_sequence().await?;
+        Ok(report)
+    }
+
+    async fn read_sidecar(&self, media: &Media) -> Option<Sidecar> {
+        match read_sidecar(sidecar_path(&media.path)).await {
+            // Only trust a sidecar which describes this media.
+            Ok(sidecar) if sidecar.id == media.id.value => Some(sidecar),
+            _ => None,
+        }
+    }
+
+    async fn restore_sidecar(&mut self, id: MediaId, sidecar: Sidecar) -> Result<(), ()> {
+        self.index_db
+            .media_provenance(
+                id,
+                sidecar.original_name.as_deref(),
+                sidecar.source.as_deref(),
+            )
+            .await?;
+
+        for label in &sidecar.labels {
+            self.index_db.media_label(id, label).await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn parse_media_id(path: &Path) -> Option<MediaId> {
+    path.file_stem()?.to_str().and_then(MediaId::from_file_base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_media_id() {
+        let id = parse_media_id(Path::new("/target/flush/000000000000002a.jpg")).unwrap();
+        assert_eq!(id.value, 42);
+
+        let id = parse_media_id(Path::new("/target/flush/00/00/000000000000002a.jpg")).unwrap();
+        assert_eq!(id.value, 42);
+
+        let id = parse_media_id(Path::new("/target/dir/000000000000002a")).unwrap();
+        assert_eq!(id.value, 42);
+
+        assert!(parse_media_id(Path::new("/target/dir/IMG_0001.jpg")).is_none());
+        assert!(parse_media_id(Path::new("/target/dir/.DS_Store")).is_none());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::api::{Media, MediaId};
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::filesystem::{FLUSH_DIRECTORY, Layout, MediaFilesystem};
+use crate::fs::fsutil::walk_files;
+use crate::fs::sidecar::{is_sidecar, sidecar_path};
+use crate::rebuild::parse_media_id;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Converts the flat flush directory into the sharded layout (see `Layout`), moving each media (and its sidecar) into its shard.
+pub struct LayoutMigrator {
+    index_db: MediaIndexDatabase,
+    filesystem: MediaFilesystem,
+}
+
+#[derive(Debug, Default)]
+pub struct ReshardReport {
+    pub moved: usize,
+    /// Media which an earlier run moved into their shard, but didn't get to sync in the index.
+    pub resumed: usize,
+    /// Files which aren't media, or which the index doesn't agree with (ex: an in-flight flush, which hasn't synced yet).
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<PathBuf>,
+}
+
+/// The part of the index which resharding reads and writes (so that it can be exercised without a database).
+trait ReshardIndex {
+    async fn media_get(&mut self, id: MediaId) -> Option<Media>;
+    async fn media_sync(&mut self, id: MediaId, path: &Path) -> Result<(), ()>;
+}
+
+impl ReshardIndex for MediaIndexDatabase {
+    async fn media_get(&mut self, id: MediaId) -> Option<Media> {
+        MediaIndexDatabase::media_get(self, id).await
+    }
+
+    async fn media_sync(&mut self, id: MediaId, path: &Path) -> Result<(), ()> {
+        MediaIndexDatabase::media_sync(self, id, path).await
+    }
+}
+
+impl LayoutMigrator {
+    pub fn new(index_db: MediaIndexDatabase, filesystem: MediaFilesystem) -> Self {
+        Self {
+            index_db,
+            filesystem,
+        }
+    }
+
+    /// Move the media directly in `flush/` into their shards, one at a time (as a File Move), so that this is resumable.
+    /// Only the media which the index records at their flat path are moved - the rest are reported, for a recheck.
+    /// A run which was interrupted between moving a media and syncing it is picked up from the shards.
+    pub async fn reshard(&mut self) -> Result<ReshardReport, ()> {
+        reshard(&mut self.index_db, &self.filesystem).await
+    }
+}
+
+async fn reshard(
+    index: &mut impl ReshardIndex,
+    filesystem: &MediaFilesystem,
+) -> Result<ReshardReport, ()> {
+    let root = filesystem.root();
+    let flush_directory = root.join(FLUSH_DIRECTORY);
+    let mut report = ReshardReport::default();
+
+    // Resume first, so that the media which this run moves aren't walked (and looked up) again.
+    for path in walk_files(&flush_directory).await.map_err(|_| ())? {
+        if path.parent() == Some(&flush_directory) || is_sidecar(&path) {
+            continue;
+        }
+
+        let Some(id) = parse_media_id(&path) else {
+            continue;
+        };
+        let Some(flat) = path.file_name().map(|name| flush_directory.join(name)) else {
+            continue;
+        };
+
+        // Only the media which the index still records at their flat path - the rest were sharded (or flushed) for good.
+        match index.media_get(id).await {
+            Some(media) if media.path == flat => {}
+            _ => continue,
+        }
+
+        match index.media_sync(id, &path).await {
+            Ok(_) if resume_sidecar(&flat, &path).await.is_ok() => report.resumed += 1,
+            _ => report.failed.push(path),
+        }
+    }
+
+    let mut entries = tokio::fs::read_dir(&flush_directory)
+        .await
+        .map_err(|_| ())?;
+
+    while let Some(entry) = entries.next_entry().await.map_err(|_| ())? {
+        let path = entry.path();
+
+        // The shards themselves, and the sidecars (which move along with their media).
+        if !entry.file_type().await.map_err(|_| ())?.is_file() || is_sidecar(&path) {
+            continue;
+        }
+
+        let Some(id) = parse_media_id(&path) else {
+            report.skipped.push(path);
+            continue;
+        };
+
+        let media = match index.media_get(id).await {
+            Some(media) if media.path == path => media,
+            _ => {
+                report.skipped.push(path);
+                continue;
+            }
+        };
+
+        let directory = root.join(Layout::Sharded.flush_directory(id));
+
+        match filesystem.move_write(&media, directory).await {
+            // If updating the index fails, the media is picked up from its shard by the next run.
+            Ok(destination) => match index.media_sync(id, &destination).await {
+                Ok(_) => report.moved += 1,
+                Err(_) => report.failed.push(path),
+            },
+            Err(_) => report.failed.push(path),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Move the sidecar which an interrupted move left at the media's `flat` path (if any) next to it, at `path`.
+async fn resume_sidecar(flat: &Path, path: &Path) -> Result<(), ()> {
+    let (from, to) = (sidecar_path(flat), sidecar_path(path));
+
+    // Unlike a rename, linking never replaces a sidecar which is already in the shard.
+    match tokio::fs::hard_link(&from, &to).await {
+        Ok(_) => tokio::fs::remove_file(&from).await.map_err(|_| ()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(_) => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tempfile::tempdir;
+
+    #[derive(Default)]
+    struct FakeIndex {
+        media: HashMap<MediaId, Media>,
+    }
+
+    impl ReshardIndex for FakeIndex {
+        async fn media_get(&mut self, id: MediaId) -> Option<Media> {
+            self.media.get(&id).map(|media| Media {
+                id: media.id,
+                path: media.path.clone(),
+                hash: media.hash,
+            })
+        }
+
+        async fn media_sync(&mut self, id: MediaId, path: &Path) -> Result<(), ()> {
+            self.media.get_mut(&id).ok_or(())?.path = path.to_path_buf();
+            Ok(())
+        }
+    }
+
+    impl FakeIndex {
+        async fn flushed(&mut self, path: PathBuf, value: i64) {
+            tokio::fs::create_dir_all(path.parent().unwrap())
+                .await
+                .unwrap();
+            tokio::fs::write(&path, b"hello").await.unwrap();
+            let id = MediaId::new(value);
+            self.media.insert(
+                id,
+                Media {
+                    id,
+                    path,
+                    hash: [0u8; 32],
+                },
+            );
+        }
+
+        fn path(&self, value: i64) -> &Path {
+            &self.media[&MediaId::new(value)].path
+        }
+    }
+
+    #[tokio::test]
+    async fn reshard_and_resume() {
+        let dir = tempdir().unwrap();
+        let flush = dir.path().join("flush");
+        let filesystem = MediaFilesystem::new(dir.path()).with_sidecars();
+        let mut index = FakeIndex::default();
+
+        // A flat media with a sidecar, and one without.
+        index.flushed(flush.join("000000000000002a.jpg"), 42).await;
+        tokio::fs::write(flush.join("000000000000002a.majdool.json"), b"{}")
+            .await
+            .unwrap();
+        index.flushed(flush.join("0000000000000100.png"), 256).await;
+
+        // An earlier run moved this media into its shard, but was interrupted before moving its sidecar and syncing it.
+        index.flushed(flush.join("000000000000002b.jpg"), 43).await;
+        tokio::fs::create_dir_all(flush.join("00/00"))
+            .await
+            .unwrap();
+        tokio::fs::rename(
+            flush.join("000000000000002b.jpg"),
+            flush.join("00/00/000000000000002b.jpg"),
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(flush.join("000000000000002b.majdool.json"), b"{}")
+            .await
+            .unwrap();
+
+        // Not media, and an unsynced (in-flight) flush.
+        tokio::fs::write(flush.join(".DS_Store"), b"")
+            .await
+            .unwrap();
+        tokio::fs::write(flush.join("000000000000002c.jpg"), b"hello")
+            .await
+            .unwrap();
+
+        let mut report = reshard(&mut index, &filesystem).await.unwrap();
+        report.skipped.sort();
+
+        assert_eq!(report.moved, 2);
+        assert_eq!(report.resumed, 1);
+        assert_eq!(
+            report.skipped,
+            vec![flush.join(".DS_Store"), flush.join("000000000000002c.jpg")]
+        );
+        assert!(report.failed.is_empty());
+
+        assert_eq!(index.path(42), flush.join("00/00/000000000000002a.jpg"));
+        assert!(flush.join("00/00/000000000000002a.majdool.json").exists());
+        assert_eq!(index.path(256), flush.join("00/01/0000000000000100.png"));
+        assert!(flush.join("00/01/0000000000000100.png").exists());
+        assert_eq!(index.path(43), flush.join("00/00/000000000000002b.jpg"));
+        assert!(flush.join("00/00/000000000000002b.majdool.json").exists());
+        assert!(!flush.join("000000000000002b.majdool.json").exists());
+
+        // Once resharded, a rerun has nothing left to do.
+        let report = reshard(&mut index, &filesystem).await.unwrap();
+        assert_eq!((report.moved, report.resumed), (0, 0));
+        assert!(report.failed.is_empty());
+    }
+}
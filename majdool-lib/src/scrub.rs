@@ -0,0 +1,154 @@
+use crate::api::{Media, Verification};
+use crate::db::database::MediaIndexDatabase;
+use crate::fs::fsutil::{HashCache, compute_cached_file_hash};
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// Every synced file should be read back at least once per quarter.
+pub const DEFAULT_PERIOD: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+pub struct MediaScrubber {
+    index_db: MediaIndexDatabase,
+    hash_cache: HashCache,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub verified: usize,
+    pub corrupt: Vec<Media>,
+    pub missing: Vec<Media>,
+    pub unreadable: Vec<Media>,
+}
+
+impl MediaScrubber {
+    pub fn new(index_db: MediaIndexDatabase) -> Self {
+        Self {
+            index_db,
+            hash_cache: HashCache::Disabled,
+        }
+    }
+
+    /// Refresh the hash cache as files are verified.
+    /// The scrubber never trusts the cache, since its job is to catch the corruption a cache would hide.
+    pub fn with_hash_cache(mut self) -> Self {
+        self.hash_cache = HashCache::Rehash;
+        self
+    }
+
+    /// Verify the next `limit` synced media, least recently verified first.
+    /// The scrubber only records the verification result - it never changes the on-disk state.
+    pub async fn scrub(&mut self, limit: u64) -> Result<ScrubReport, ()> {
+        let mut report = ScrubReport::default();
+
+        for media in self.index_db.media_verification_candidates(limit).await? {
+            let verification = verify(&media, self.hash_cache).await;
+            self.index_db.media_verify(media.id, verification).await?;
+
+            match verification {
+                Verification::Verified => report.verified += 1,
+                Verification::Corrupt => report.corrupt.push(media),
+                Verification::Missing => report.missing.push(media),
+                Verification::Unreadable => report.unreadable.push(media),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// The number of media to scrub every `interval`, such that the whole index is verified within `period`.
+    pub async fn batch_size(&mut self, interval: Duration, period: Duration) -> Result<u64, ()> {
+        let total = self.index_db.media_synced_count().await?;
+        Ok(batch_size(total, interval, period))
+    }
+
+    /// The number of synced media which have not been verified within `period`.
+    pub async fn overdue(&mut self, period: Duration) -> Result<i64, ()> {
+        self.index_db.media_verification_overdue_count(period).await
+    }
+}
+
+/// Re-hash the on-disk file and compare it against the indexed hash.
+pub async fn verify(media: &Media, hash_cache: HashCache) -> Verification {
+    match compute_cached_file_hash(&media.path, hash_cache).await {
+        Ok(hash) if hash == media.hash => Verification::Verified,
+        Ok(_) => Verification::Corrupt,
+        Err(e) if e.kind() == ErrorKind::NotFound => Verification::Missing,
+        Err(_) => Verification::Unreadable,
+    }
+}
+
+fn batch_size(total: i64, interval: Duration, period: Duration) -> u64 {
+    let batches = (period.as_secs_f64() / interval.as_secs_f64())
+        .floor()
+        .max(1.0);
+    ((total.max(0) as f64 / batches).ceil() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::MediaId;
+    use crate::fs::fsutil::FileHash;
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+
+    fn media(path: impl Into<std::path::PathBuf>, hash: FileHash) -> Media {
+        Media {
+            id: MediaId::new(1),
+            path: path.into(),
+            hash,
+        }
+    }
+
+    #[tokio::test]
+    async fn verify_verified() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+
+        let verification = verify(
+            &media(&path, Sha256::digest(b"hello").into()),
+            HashCache::Disabled,
+        )
+        .await;
+        assert_eq!(verification, Verification::Verified);
+    }
+
+    #[tokio::test]
+    async fn verify_corrupt() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        tokio::fs::write(&path, b"hellp").await.unwrap();
+
+        let verification = verify(
+            &media(&path, Sha256::digest(b"hello").into()),
+            HashCache::Disabled,
+        )
+        .await;
+        assert_eq!(verification, Verification::Corrupt);
+    }
+
+    #[tokio::test]
+    async fn verify_missing() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("missing.txt");
+
+        let verification = verify(
+            &media(&path, Sha256::digest(b"hello").into()),
+            HashCache::Disabled,
+        )
+        .await;
+        assert_eq!(verification, Verification::Missing);
+    }
+
+    #[test]
+    fn batch_size_covers_period() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(batch_size(900, day, 90 * day), 10);
+        assert_eq!(batch_size(901, day, 90 * day), 11);
+        assert_eq!(batch_size(5, day, 90 * day), 1);
+        assert_eq!(batch_size(0, day, 90 * day), 1);
+        // An interval longer than the period must still verify everything in each batch.
+        assert_eq!(batch_size(900, 100 * day, 90 * day), 900);
+    }
+}
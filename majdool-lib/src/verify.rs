@@ -0,0 +1,157 @@
+use crate::api::MediaId;
+use crate::db::database::MediaIndexDatabase;
+use crate::filter::Filter;
+use crate::fs::fsutil::{compute_file_hash, content_wise_equals, walk_files};
+use crate::fs::receipt::is_receipt;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Checks that every file on a device (ex: a camera card) exists on the target, so that the device is safe to wipe.
+/// The verifier only ever reads - from the device, the index and the target.
+pub struct DeviceVerifier {
+    index_db: MediaIndexDatabase,
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceVerifyReport {
+    pub verified: usize,
+    /// The files with no content-wise match on the target.
+    pub missing: Vec<PathBuf>,
+    /// The files which couldn't be compared (either side failed to read).
+    pub unreadable: Vec<PathBuf>,
+    /// The files which the filter turned away (they're never flushed, so they aren't on the target either).
+    pub skipped: Vec<PathBuf>,
+}
+
+impl DeviceVerifyReport {
+    /// Whether every file on the device was verified.
+    /// The skipped files were never imported, so they only pass when explicitly `allow_skipped` (ex: camera junk).
+    pub fn safe_to_wipe(&self, allow_skipped: bool) -> bool {
+        self.missing.is_empty()
+            && self.unreadable.is_empty()
+            && (allow_skipped || self.skipped.is_empty())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Presence {
+    Present(MediaId),
+    Missing,
+    Unreadable,
+}
+
+impl DeviceVerifier {
+    pub fn new(index_db: MediaIndexDatabase) -> Self {
+        Self { index_db }
+    }
+
+    /// Verify each file under the `source` against its indexed match.
+    /// A hash match alone isn't enough - the target file must still match the source byte-for-byte.
+    /// The files which the `filter` doesn't accept are skipped, just as the drive flush skips them.
+    pub async fn verify(
+        &mut self,
+        source: impl AsRef<Path>,
+        filter: &Filter,
+    ) -> Result<DeviceVerifyReport, ()> {
+        let source = source.as_ref();
+        let mut report = DeviceVerifyReport::default();
+
+        for path in walk_files(source).await.map_err(|_| ())? {
+            if is_receipt(&path) {
+                continue;
+            }
+
+            let Ok(metadata) = tokio::fs::metadata(&path).await else {
+                report.unreadable.push(path);
+                continue;
+            };
+
+            if !filter.accepts(source, &path, metadata.len()) {
+                report.skipped.push(path);
+                continue;
+            }
+
+            match self.presence(&path).await {
+                Presence::Present(_) => report.verified += 1,
+                Presence::Missing => report.missing.push(path),
+                Presence::Unreadable => report.unreadable.push(path),
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn presence(&mut self, path: &Path) -> Presence {
+        let Ok(hash) = compute_file_hash(path).await else {
+            return Presence::Unreadable;
+        };
+
+        match self.index_db.media_lookup(hash).await {
+            Some(media) => compare(path, media.id, &media.path).await,
+            None => Presence::Missing,
+        }
+    }
+}
+
+async fn compare(source: &Path, id: MediaId, target: &Path) -> Presence {
+    match content_wise_equals(source, target).await {
+        Ok(true) => Presence::Present(id),
+        // A hash collision.
+        Ok(false) => Presence::Missing,
+        // The match has been moved or deleted from the target (unless it's the source that's gone).
+        Err(e) if e.kind() == ErrorKind::NotFound => match tokio::fs::try_exists(source).await {
+            Ok(true) => Presence::Missing,
+            _ => Presence::Unreadable,
+        },
+        Err(_) => Presence::Unreadable,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn skipped_files_are_not_safe_to_wipe() {
+        let mut report = DeviceVerifyReport {
+            verified: 1,
+            ..Default::default()
+        };
+        assert!(report.safe_to_wipe(false));
+
+        report
+            .skipped
+            .push(PathBuf::from("/media/card/DCIM/IMG_0001.CR3"));
+        assert!(!report.safe_to_wipe(false));
+        assert!(report.safe_to_wipe(true));
+
+        report
+            .missing
+            .push(PathBuf::from("/media/card/DCIM/IMG_0002.jpg"));
+        assert!(!report.safe_to_wipe(true));
+    }
+
+    #[tokio::test]
+    async fn compare_present_and_missing() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("a.jpg");
+        let target = dir.path().join("0000000000000001.jpg");
+        let other = dir.path().join("0000000000000002.jpg");
+        tokio::fs::write(&source, b"hello").await.unwrap();
+        tokio::fs::write(&target, b"hello").await.unwrap();
+        tokio::fs::write(&other, b"hellp").await.unwrap();
+        let id = MediaId::new(1);
+
+        assert_eq!(compare(&source, id, &target).await, Presence::Present(id));
+        assert_eq!(compare(&source, id, &other).await, Presence::Missing);
+        assert_eq!(
+            compare(&source, id, &dir.path().join("gone.jpg")).await,
+            Presence::Missing
+        );
+        assert_eq!(
+            compare(&dir.path().join("gone.jpg"), id, &target).await,
+            Presence::Unreadable
+        );
+    }
+}
@@ -0,0 +1,838 @@
+mod output;
+use output::{
+    AdoptOutput, CheckOutput, ErrorOutput, FlushOutput, GcOutput, InitOutput, ListOutput,
+    LookupOutput, MediaOutput, MigrateOutput, MoveOutput, ReshardOutput, ShowOutput, StatusOutput,
+    VerifyOutput, emit,
+};
+
+use blarg::{
+    CommandLineParser, Condition, Optional, Parameter, Scalar, SubCommand, Switch, derive::*,
+    prelude::*,
+};
+use majdool_lib::adopt::LibraryAdopter;
+use majdool_lib::api::{MediaId, MediaState};
+use majdool_lib::config::Config;
+use majdool_lib::db::database::{MediaIndexDatabase, connect};
+use majdool_lib::fs::filesystem::{FLUSH_DIRECTORY, Layout};
+use majdool_lib::fs::fsutil::compute_file_hash;
+use majdool_lib::fs::receipt::{run_id, write_receipt};
+use majdool_lib::library::{check_library, init_library};
+use majdool_lib::media::MediaSystem;
+use majdool_lib::monitor::ConsistencyMonitor;
+use majdool_lib::reshard::LayoutMigrator;
+use majdool_lib::scrub::DEFAULT_PERIOD;
+use majdool_lib::verify::DeviceVerifier;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq, Hash, BlargChoices)]
+enum Command {
+    #[blarg(help = "Flush every file beneath a directory onto the target")]
+    Flush,
+    #[blarg(help = "Index an existing library on the target, in place")]
+    Adopt,
+    #[blarg(help = "Look up the media matching a file's content")]
+    Lookup,
+    #[blarg(help = "List the synced media")]
+    Ls,
+    #[blarg(help = "Show everything the index records for a media")]
+    Show,
+    #[blarg(help = "Move a media into a directory on the target")]
+    Move,
+    #[blarg(help = "Delete the rows of failed (un-synced) flushes")]
+    Gc,
+    #[blarg(help = "Recheck the target against the index")]
+    Check,
+    #[blarg(help = "Apply the pending database migrations")]
+    Migrate,
+    #[blarg(help = "Summarize the state of the index")]
+    Status,
+    #[blarg(help = "Verify that every file on a device exists on the target")]
+    VerifyDevice,
+    #[blarg(help = "Convert the flat flush directory into the sharded layout")]
+    Reshard,
+    #[blarg(help = "Initialize the target, and record its library in the database")]
+    Init,
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Flush => write!(f, "flush"),
+            Command::Adopt => write!(f, "adopt"),
+            Command::Lookup => write!(f, "lookup"),
+            Command::Ls => write!(f, "ls"),
+            Command::Show => write!(f, "show"),
+            Command::Move => write!(f, "move"),
+            Command::Gc => write!(f, "gc"),
+            Command::Check => write!(f, "check"),
+            Command::Migrate => write!(f, "migrate"),
+            Command::Status => write!(f, "status"),
+            Command::VerifyDevice => write!(f, "verify-device"),
+            Command::Reshard => write!(f, "reshard"),
+            Command::Init => write!(f, "init"),
+        }
+    }
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "flush" => Ok(Command::Flush),
+            "adopt" => Ok(Command::Adopt),
+            "lookup" => Ok(Command::Lookup),
+            "ls" => Ok(Command::Ls),
+            "show" => Ok(Command::Show),
+            "move" => Ok(Command::Move),
+            "gc" => Ok(Command::Gc),
+            "check" => Ok(Command::Check),
+            "migrate" => Ok(Command::Migrate),
+            "status" => Ok(Command::Status),
+            "verify-device" => Ok(Command::VerifyDevice),
+            "reshard" => Ok(Command::Reshard),
+            "init" => Ok(Command::Init),
+            _ => Err(format!("unknown: {}", value)),
+        }
+    }
+}
+
+#[derive(BlargParser)]
+#[blarg(program = "majdool", initializer = initial)]
+struct Args {
+    #[blarg(option, help = "Path to the majdool config (TOML)")]
+    config: String,
+    #[blarg(help = "Print the output as JSON")]
+    json: bool,
+    #[blarg(
+        command = (Command::Flush, FlushArgs),
+        command = (Command::Adopt, AdoptArgs),
+        command = (Command::Lookup, LookupArgs),
+        command = (Command::Ls, LsArgs),
+        command = (Command::Show, ShowArgs),
+        command = (Command::Move, MoveArgs),
+        command = (Command::Gc, GcArgs),
+        command = (Command::Check, CheckArgs),
+        command = (Command::Migrate, MigrateArgs),
+        command = (Command::Status, StatusArgs),
+        command = (Command::VerifyDevice, VerifyDeviceArgs),
+        command = (Command::Reshard, ReshardArgs),
+        command = (Command::Init, InitArgs),
+        choices,
+    )]
+    command: Command,
+}
+
+impl Args {
+    fn initial() -> Self {
+        Self {
+            config: "majdool.toml".to_string(),
+            json: false,
+            // This is an argument, so it is always overwritten.
+            command: Command::Status,
+        }
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Flush every file beneath a directory (ex: a memory card) onto the target.")]
+struct FlushArgs {
+    #[blarg(help = "Directory path to flush from")]
+    directory: String,
+    #[blarg(
+        help = "Write a receipt (.majdool-receipt.json) to the directory, once every file is flushed"
+    )]
+    receipt: bool,
+}
+
+impl FlushArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Index the existing files beneath the target (or a path beneath it) in place, renaming each to its media id within its directory. Resumable."
+)]
+struct AdoptArgs {
+    #[blarg(
+        option,
+        help = "Path beneath the target to adopt (defaults to the whole target)"
+    )]
+    path: Option<String>,
+}
+
+impl AdoptArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Look up the media matching a file's content (exits non-zero when there is none).")]
+struct LookupArgs {
+    #[blarg(help = "File path to look up")]
+    file: String,
+}
+
+impl LookupArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "List the synced media, in id order.")]
+struct LsArgs {
+    #[blarg(option, help = "Only list the media with this label")]
+    label: Option<String>,
+    #[blarg(option, help = "The maximum number of media to list")]
+    limit: u64,
+}
+
+impl LsArgs {
+    fn initial() -> Self {
+        Self {
+            label: None,
+            limit: 100,
+        }
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Show everything the index records for a media, in any state.")]
+struct ShowArgs {
+    #[blarg(help = "The media id (or its file base, prefixed by 0x when it's all digits)")]
+    id: String,
+}
+
+impl ShowArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Move a media into a directory on the target (see File Moves).")]
+struct MoveArgs {
+    #[blarg(help = "The media id (or its file base, prefixed by 0x when it's all digits)")]
+    id: String,
+    #[blarg(help = "Directory path, relative to the target")]
+    directory: String,
+}
+
+impl MoveArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Delete the rows of failed (un-synced) flushes. Rows whose file is still in the flush directory are only reported."
+)]
+struct GcArgs {
+    #[blarg(help = "Report the rows, without deleting them")]
+    dry_run: bool,
+}
+
+impl GcArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Recheck the target (or a path beneath it) against the index, fixing the index.")]
+struct CheckArgs {
+    #[blarg(
+        option,
+        help = "Path beneath the target to recheck (defaults to the whole target)"
+    )]
+    path: Option<String>,
+}
+
+impl CheckArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Apply the pending database migrations.")]
+struct MigrateArgs {
+    #[blarg(
+        option,
+        help = "Mark a database which was migrated by hand as being at this version first"
+    )]
+    baseline: Option<i32>,
+}
+
+impl MigrateArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(about = "Summarize the state of the index.")]
+struct StatusArgs {}
+
+impl StatusArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Verify that every file on a device (ex: a camera card) exists on the target, byte-for-byte, before wiping it. Exits non-zero, listing the offenders, when any file doesn't (including the files which the filter skips)."
+)]
+struct VerifyDeviceArgs {
+    #[blarg(help = "Directory path of the device to verify")]
+    directory: String,
+    #[blarg(help = "Wipe the files which the filter skips, although they were never imported")]
+    allow_skipped: bool,
+}
+
+impl VerifyDeviceArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Move the media of the flat flush directory into their shards (flush/00/00/...), updating the index. Refused unless the config sets `layout = \"sharded\"`, so that new flushes are sharded too. Resumable."
+)]
+struct ReshardArgs {}
+
+impl ReshardArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Default, BlargSubParser)]
+#[blarg(
+    about = "Initialize the target (creating its flush directory, and checking that it's writable and on a single device), marking it with a library id which is also recorded in the database. Idempotent."
+)]
+struct InitArgs {}
+
+impl InitArgs {
+    fn initial() -> Self {
+        Self::default()
+    }
+}
+
+/// The root arguments, followed by those of each command (in their declaration order).
+type Parsed = (
+    Args,
+    FlushArgs,
+    AdoptArgs,
+    LookupArgs,
+    LsArgs,
+    ShowArgs,
+    MoveArgs,
+    GcArgs,
+    CheckArgs,
+    MigrateArgs,
+    StatusArgs,
+    VerifyDeviceArgs,
+    ReshardArgs,
+    InitArgs,
+);
+
+#[tokio::main]
+async fn main() {
+    let (
+        args,
+        flush,
+        adopt,
+        lookup,
+        ls,
+        show,
+        move_args,
+        gc,
+        check,
+        migrate,
+        _,
+        verify_device,
+        _,
+        _,
+    ): Parsed = Args::blarg_parse();
+    let json = args.json;
+
+    let result = match Config::load(&args.config).await {
+        Ok(config) => match args.command {
+            Command::Flush => run_flush(&config, flush, json).await,
+            Command::Adopt => run_adopt(&config, adopt, json).await,
+            Command::Lookup => run_lookup(&config, lookup, json).await,
+            Command::Ls => run_ls(&config, ls, json).await,
+            Command::Show => run_show(&config, show, json).await,
+            Command::Move => run_move(&config, move_args, json).await,
+            Command::Gc => run_gc(&config, gc, json).await,
+            Command::Check => run_check(&config, check, json).await,
+            Command::Migrate => run_migrate(&config, migrate, json).await,
+            Command::Status => run_status(&config, json).await,
+            Command::VerifyDevice => run_verify_device(&config, verify_device, json).await,
+            Command::Reshard => run_reshard(&config, json).await,
+            Command::Init => run_init(&config, json).await,
+        },
+        Err(e) => Err(format!("invalid config: {e}")),
+    };
+
+    if let Err(error) = result {
+        emit(json, &ErrorOutput { error });
+        exit(1);
+    }
+}
+
+async fn run_flush(config: &Config, args: FlushArgs, json: bool) -> Result<(), String> {
+    let (target, index_db) = library(config).await?;
+    let source = existing_directory(Path::new(&args.directory), "source")?;
+    let mut system = MediaSystem::new(index_db, config.filesystem(target));
+    let report = system
+        .flush_drive(&source, &config.filter)
+        .await
+        .map_err(|_| format!("failed to flush {source:?}"))?;
+    let failed = !report.failed.is_empty();
+    let receipt = report.receipt(run_id(), &source);
+    emit(json, &FlushOutput::from(report));
+
+    if args.receipt
+        && let Some(receipt) = receipt
+    {
+        write_receipt(&source, &receipt)
+            .await
+            .map_err(|e| format!("failed to write the receipt to {source:?}: {e}"))?;
+    }
+
+    if failed {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run_adopt(config: &Config, args: AdoptArgs, json: bool) -> Result<(), String> {
+    let (target, index_db) = library(config).await?;
+    let path = beneath_target(&target, args.path)?;
+    let mut adopter = LibraryAdopter::new(index_db, config.filesystem(target));
+    let report = adopter
+        .adopt(&path)
+        .await
+        .map_err(|_| format!("failed to adopt {path:?}"))?;
+    let failed = !report.failed.is_empty();
+    emit(json, &AdoptOutput::from(report));
+
+    if failed {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run_lookup(config: &Config, args: LookupArgs, json: bool) -> Result<(), String> {
+    let hash = compute_file_hash(&args.file)
+        .await
+        .map_err(|e| format!("failed to hash {:?}: {e}", args.file))?;
+    let media = index_db(config).await?.media_lookup(hash).await;
+    let found = media.is_some();
+    emit(
+        json,
+        &LookupOutput {
+            hash: hex::encode(hash),
+            media: media.as_ref().map(MediaOutput::from),
+        },
+    );
+
+    if !found {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run_ls(config: &Config, args: LsArgs, json: bool) -> Result<(), String> {
+    let media = index_db(config)
+        .await?
+        .media_list(args.label.as_deref(), args.limit)
+        .await
+        .map_err(|_| "failed to list the media".to_string())?;
+    emit(
+        json,
+        &ListOutput {
+            media: media.iter().map(MediaOutput::from).collect(),
+        },
+    );
+    Ok(())
+}
+
+async fn run_show(config: &Config, args: ShowArgs, json: bool) -> Result<(), String> {
+    let id = parse_id(&args.id)?;
+    let mut index_db = index_db(config).await?;
+    let details = index_db
+        .media_details(id)
+        .await
+        .map_err(|_| format!("failed to read media {}", args.id))?
+        .ok_or(format!("media not found: {}", args.id))?;
+    let labels = index_db
+        .media_labels(id)
+        .await
+        .map_err(|_| format!("failed to read the labels of media {}", args.id))?;
+    emit(json, &ShowOutput::new(details, labels));
+    Ok(())
+}
+
+async fn run_move(config: &Config, args: MoveArgs, json: bool) -> Result<(), String> {
+    let id = parse_id(&args.id)?;
+    let (target, index_db) = library(config).await?;
+    let directory = Path::new(&args.directory);
+
+    // Keep the media on the target (the index only tracks the target).
+    if !directory
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(format!(
+            "directory must be relative to the target: {directory:?}"
+        ));
+    }
+
+    let mut system = MediaSystem::new(index_db, config.filesystem(target.clone()));
+    let path = system
+        .move_media(id, target.join(directory))
+        .await
+        .map_err(|_| format!("failed to move media {}", args.id))?;
+    emit(
+        json,
+        &MoveOutput {
+            id: id.value,
+            file_base: id.file_base(),
+            path,
+        },
+    );
+    Ok(())
+}
+
+/// An un-synced row is left behind by a failed or interrupted flush (see File Flush Procedure) or adoption.
+/// When its file made it into the flush directory (in either layout, or was renamed in place, by an adoption), the flush may still be in progress (or need a closer look), so the row is kept.
+async fn run_gc(config: &Config, args: GcArgs, json: bool) -> Result<(), String> {
+    let (target, mut index_db) = library(config).await?;
+    let flushed = media_files(&target.join(FLUSH_DIRECTORY)).await?;
+    let unsynced = index_db
+        .media_unsynced()
+        .await
+        .map_err(|_| "failed to read the un-synced media".to_string())?;
+    let mut output = GcOutput {
+        dry_run: args.dry_run,
+        deleted: Vec::default(),
+        kept: Vec::default(),
+    };
+
+    for state in unsynced {
+        let mut media = MediaOutput::from(&state);
+
+        if let Some(file) = flushed.get(&media.file_base) {
+            media.path = Some(file.clone());
+            output.kept.push(media);
+            continue;
+        }
+
+        if let Some(file) = media_files(&target.join(Layout::Sharded.flush_directory(state.id)))
+            .await?
+            .remove(&media.file_base)
+        {
+            media.path = Some(file);
+            output.kept.push(media);
+            continue;
+        }
+
+        if let Some(file) = adopted_file(&mut index_db, &target, &state).await? {
+            media.path = Some(file);
+            output.kept.push(media);
+            continue;
+        }
+
+        if !args.dry_run
+            && !index_db
+                .media_delete(state.id)
+                .await
+                .map_err(|_| format!("failed to delete media {}", media.file_base))?
+        {
+            // It was synced (or deleted) in the meantime.
+            continue;
+        }
+
+        output.deleted.push(media);
+    }
+
+    emit(json, &output);
+    Ok(())
+}
+
+async fn run_check(config: &Config, args: CheckArgs, json: bool) -> Result<(), String> {
+    let (target, index_db) = library(config).await?;
+    let path = beneath_target(&target, args.path)?;
+
+    let mut monitor = ConsistencyMonitor::new(index_db);
+    let rechecks = monitor
+        .recheck(&path)
+        .await
+        .map_err(|_| format!("failed to recheck {path:?}"))?;
+    emit(json, &CheckOutput::new(path, rechecks));
+    Ok(())
+}
+
+async fn run_init(config: &Config, json: bool) -> Result<(), String> {
+    let target = existing_directory(&config.target, "target")?;
+    let library = init_library(&mut index_db(config).await?, &target).await?;
+    emit(json, &InitOutput { target, library });
+    Ok(())
+}
+
+async fn run_reshard(config: &Config, json: bool) -> Result<(), String> {
+    // Otherwise, the new flushes would keep landing in the flat directory, and each reshard would have to move them again.
+    if config.layout != Layout::Sharded {
+        return Err(
+            "the config's layout isn't sharded (set `layout = \"sharded\"` first, then reshard)"
+                .to_string(),
+        );
+    }
+
+    let (target, index_db) = library(config).await?;
+    let mut migrator = LayoutMigrator::new(index_db, config.filesystem(target));
+    let report = migrator
+        .reshard()
+        .await
+        .map_err(|_| "failed to read the flush directory".to_string())?;
+    let failed = !report.failed.is_empty();
+    emit(json, &ReshardOutput::from(report));
+
+    if failed {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+async fn run_migrate(config: &Config, args: MigrateArgs, json: bool) -> Result<(), String> {
+    let applied = index_db(config)
+        .await?
+        .migrate(args.baseline)
+        .await
+        .map_err(|_| "migration failed".to_string())?;
+    emit(json, &MigrateOutput { applied });
+    Ok(())
+}
+
+async fn run_status(config: &Config, json: bool) -> Result<(), String> {
+    let mut index_db = index_db(config).await?;
+    let failed = |what: &str| format!("failed to read the {what}");
+    let counts = index_db
+        .media_counts()
+        .await
+        .map_err(|_| failed("media counts"))?;
+    let verification_overdue = index_db
+        .media_verification_overdue_count(DEFAULT_PERIOD)
+        .await
+        .map_err(|_| failed("verification count"))?;
+    let dead_letters = index_db
+        .dead_letters(None)
+        .await
+        .map_err(|_| failed("dead letters"))?;
+    let pending_migrations = index_db
+        .migrations_pending()
+        .await
+        .map_err(|_| failed("migrations"))?;
+    emit(
+        json,
+        &StatusOutput {
+            synced: counts.synced,
+            unsynced: counts.unsynced,
+            lost: counts.lost,
+            verification_overdue,
+            dead_letters: dead_letters.len(),
+            pending_migrations,
+        },
+    );
+    Ok(())
+}
+
+/// Never writes to the index or the target.
+async fn run_verify_device(
+    config: &Config,
+    args: VerifyDeviceArgs,
+    json: bool,
+) -> Result<(), String> {
+    let (_, index_db) = library(config).await?;
+    let source = existing_directory(Path::new(&args.directory), "device")?;
+    let mut verifier = DeviceVerifier::new(index_db);
+    let report = verifier
+        .verify(&source, &config.filter)
+        .await
+        .map_err(|_| format!("failed to verify {source:?}"))?;
+    let safe_to_wipe = report.safe_to_wipe(args.allow_skipped);
+    emit(json, &VerifyOutput::new(source, report, args.allow_skipped));
+
+    if !safe_to_wipe {
+        exit(1);
+    }
+
+    Ok(())
+}
+
+async fn index_db(config: &Config) -> Result<MediaIndexDatabase, String> {
+    connect(&config.database)
+        .await
+        .map_err(|_| format!("failed to connect to the database: {}", config.database))
+}
+
+/// The target and the database, once they're checked to belong to the same library (see Target Initialization).
+async fn library(config: &Config) -> Result<(PathBuf, MediaIndexDatabase), String> {
+    let target = existing_directory(&config.target, "target")?;
+    let mut index_db = index_db(config).await?;
+    check_library(&mut index_db, &target).await?;
+    Ok((target, index_db))
+}
+
+/// The `path` (defaulting to the whole target), which must be beneath the target.
+fn beneath_target(target: &Path, path: Option<String>) -> Result<PathBuf, String> {
+    let path = match path {
+        Some(path) => Path::new(&path)
+            .canonicalize()
+            .map_err(|e| format!("invalid path {path:?}: {e}"))?,
+        None => target.to_path_buf(),
+    };
+
+    if !path.starts_with(target) {
+        return Err(format!("path must be beneath the target: {path:?}"));
+    }
+
+    Ok(path)
+}
+
+/// Accept either the numeric id, or its file base (ex: from a flushed file's name).
+/// A value of only decimal digits is the numeric id - so a file base without any of `a-f` needs the `0x` prefix.
+fn parse_id(value: &str) -> Result<MediaId, String> {
+    let id = match value.strip_prefix("0x") {
+        Some(file_base) => MediaId::from_file_base(file_base),
+        None if value.bytes().all(|b| b.is_ascii_digit()) => {
+            value.parse().ok().filter(|v| *v >= 0).map(MediaId::new)
+        }
+        None => MediaId::from_file_base(value),
+    };
+
+    id.ok_or(format!("invalid media id: {value}"))
+}
+
+fn existing_directory(path: &Path, name: &str) -> Result<PathBuf, String> {
+    match path.canonicalize() {
+        Ok(path) if path.is_dir() => Ok(path),
+        _ => Err(format!(
+            "invalid {name} path (must exist and be a directory): {path:?}"
+        )),
+    }
+}
+
+/// The file which an adoption renamed the un-synced media's source to, if any (it's renamed within the source's directory).
+async fn adopted_file(
+    index_db: &mut MediaIndexDatabase,
+    target: &Path,
+    state: &MediaState,
+) -> Result<Option<PathBuf>, String> {
+    let details = index_db
+        .media_details(state.id)
+        .await
+        .map_err(|_| format!("failed to read media {}", state.id.file_base()))?;
+    let Some(directory) = details
+        .and_then(|details| details.source)
+        .map(PathBuf::from)
+        .and_then(|source| source.parent().map(Path::to_path_buf))
+        .filter(|directory| directory.starts_with(target))
+    else {
+        return Ok(None);
+    };
+
+    Ok(media_files(&directory).await?.remove(&state.id.file_base()))
+}
+
+/// The files in the `directory`, by their file base.
+async fn media_files(directory: &Path) -> Result<HashMap<String, PathBuf>, String> {
+    let mut files = HashMap::default();
+    let mut entries = match tokio::fs::read_dir(directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(format!("failed to read {directory:?}: {e}")),
+    };
+
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| format!("failed to read {directory:?}: {e}"))?
+    {
+        let path = entry.path();
+
+        if let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) {
+            files.insert(stem.to_string(), path);
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn parse_id_numeric_or_file_base() {
+        assert_eq!(parse_id("42").unwrap(), MediaId::new(42));
+        assert_eq!(parse_id("000000000000002a").unwrap(), MediaId::new(42));
+        assert_eq!(parse_id("0x000000000000002a").unwrap(), MediaId::new(42));
+
+        // 16 decimal digits are the numeric id, unless they're prefixed as a file base.
+        assert_eq!(
+            parse_id("1000000000000000").unwrap(),
+            MediaId::new(1_000_000_000_000_000)
+        );
+        assert_eq!(parse_id("0x0000000000000100").unwrap(), MediaId::new(256));
+
+        assert!(parse_id("").is_err());
+        assert!(parse_id("-1").is_err());
+        assert!(parse_id("2a").is_err());
+        assert!(parse_id("0x2a").is_err());
+        assert!(parse_id("8000000000000000a").is_err());
+        assert!(parse_id("0xffffffffffffffff").is_err());
+    }
+
+    #[test]
+    fn beneath_target_paths() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().canonicalize().unwrap().join("target");
+        std::fs::create_dir_all(target.join("2019")).unwrap();
+        std::fs::create_dir_all(dir.path().join("elsewhere")).unwrap();
+
+        assert_eq!(beneath_target(&target, None).unwrap(), target);
+        assert_eq!(
+            beneath_target(&target, Some(target.join("2019").display().to_string())).unwrap(),
+            target.join("2019")
+        );
+
+        // Paths are resolved before they're checked, so `..` can't escape the target.
+        let escape = target.join("2019/../../elsewhere");
+        assert!(beneath_target(&target, Some(escape.display().to_string())).is_err());
+        assert!(beneath_target(&target, Some(dir.path().display().to_string())).is_err());
+
+        // And a path which doesn't exist is rejected.
+        let missing = target.join("2020");
+        assert!(beneath_target(&target, Some(missing.display().to_string())).is_err());
+    }
+}
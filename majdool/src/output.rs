@@ -0,0 +1,507 @@
+use majdool_lib::adopt::AdoptReport;
+use majdool_lib::api::{Media, MediaDetails, MediaId, MediaState};
+use majdool_lib::fs::fsutil::FileHash;
+use majdool_lib::media::DriveFlushReport;
+use majdool_lib::monitor::Recheck;
+use majdool_lib::reshard::ReshardReport;
+use majdool_lib::verify::DeviceVerifyReport;
+use serde::Serialize;
+use std::fmt::{Display, Formatter, Result};
+use std::path::PathBuf;
+
+/// Print the `output` as JSON, or in its human readable form.
+pub fn emit(json: bool, output: &(impl Serialize + Display)) {
+    if json {
+        // Our outputs are plain structs, so they always serialize.
+        println!("{}", serde_json::to_string_pretty(output).unwrap());
+    } else {
+        print!("{output}");
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorOutput {
+    pub error: String,
+}
+
+impl Display for ErrorOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "{}", self.error)
+    }
+}
+
+#[derive(Serialize)]
+pub struct MediaOutput {
+    pub id: i64,
+    pub file_base: String,
+    pub path: Option<PathBuf>,
+    pub hash: String,
+}
+
+impl MediaOutput {
+    fn new(id: MediaId, path: Option<PathBuf>, hash: &FileHash) -> Self {
+        Self {
+            id: id.value,
+            file_base: id.file_base(),
+            path,
+            hash: hex::encode(hash),
+        }
+    }
+}
+
+impl From<&Media> for MediaOutput {
+    fn from(media: &Media) -> Self {
+        Self::new(media.id, Some(media.path.clone()), &media.hash)
+    }
+}
+
+impl From<&MediaState> for MediaOutput {
+    fn from(state: &MediaState) -> Self {
+        Self::new(state.id, state.path.clone(), &state.hash)
+    }
+}
+
+impl Display for MediaOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match &self.path {
+            Some(path) => write!(f, "{} {path:?}", self.file_base),
+            None => write!(f, "{} (no path)", self.file_base),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FlushOutput {
+    pub files: usize,
+    pub bytes: u64,
+    pub failed: Vec<PathBuf>,
+    pub skipped: usize,
+}
+
+impl From<DriveFlushReport> for FlushOutput {
+    fn from(report: DriveFlushReport) -> Self {
+        Self {
+            files: report.files,
+            bytes: report.bytes,
+            failed: report.failed,
+            skipped: report.skipped,
+        }
+    }
+}
+
+impl Display for FlushOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for path in &self.failed {
+            writeln!(f, "failed: {path:?}")?;
+        }
+
+        writeln!(
+            f,
+            "flushed {} files ({} bytes), {} failed, {} skipped",
+            self.files,
+            self.bytes,
+            self.failed.len(),
+            self.skipped
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct AdoptOutput {
+    pub adopted: usize,
+    pub resumed: usize,
+    pub existing: usize,
+    pub duplicates: Vec<PathBuf>,
+    pub conflicts: Vec<PathBuf>,
+    pub failed: Vec<PathBuf>,
+}
+
+impl From<AdoptReport> for AdoptOutput {
+    fn from(report: AdoptReport) -> Self {
+        Self {
+            adopted: report.adopted,
+            resumed: report.resumed,
+            existing: report.existing,
+            duplicates: report.duplicates,
+            conflicts: report.conflicts,
+            failed: report.failed,
+        }
+    }
+}
+
+impl Display for AdoptOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for path in &self.duplicates {
+            writeln!(f, "duplicate: {path:?}")?;
+        }
+
+        for path in &self.conflicts {
+            writeln!(f, "conflict: {path:?}")?;
+        }
+
+        for path in &self.failed {
+            writeln!(f, "failed: {path:?}")?;
+        }
+
+        writeln!(
+            f,
+            "adopted {} ({} resumed, {} already adopted), {} duplicates, {} conflicts, {} failed",
+            self.adopted,
+            self.resumed,
+            self.existing,
+            self.duplicates.len(),
+            self.conflicts.len(),
+            self.failed.len()
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct InitOutput {
+    pub target: PathBuf,
+    pub library: String,
+}
+
+impl Display for InitOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "{:?} holds library {}", self.target, self.library)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ReshardOutput {
+    pub moved: usize,
+    pub resumed: usize,
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<PathBuf>,
+}
+
+impl From<ReshardReport> for ReshardOutput {
+    fn from(report: ReshardReport) -> Self {
+        Self {
+            moved: report.moved,
+            resumed: report.resumed,
+            skipped: report.skipped,
+            failed: report.failed,
+        }
+    }
+}
+
+impl Display for ReshardOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for path in &self.skipped {
+            writeln!(f, "skipped: {path:?}")?;
+        }
+
+        for path in &self.failed {
+            writeln!(f, "failed: {path:?}")?;
+        }
+
+        writeln!(
+            f,
+            "moved {} into their shards ({} resumed), {} skipped, {} failed",
+            self.moved,
+            self.resumed,
+            self.skipped.len(),
+            self.failed.len()
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct LookupOutput {
+    pub hash: String,
+    pub media: Option<MediaOutput>,
+}
+
+impl Display for LookupOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match &self.media {
+            Some(media) => writeln!(f, "{}: {media}", self.hash),
+            None => writeln!(f, "{}: not found", self.hash),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ListOutput {
+    pub media: Vec<MediaOutput>,
+}
+
+impl Display for ListOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for media in &self.media {
+            writeln!(f, "{media}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct ShowOutput {
+    #[serde(flatten)]
+    pub media: MediaOutput,
+    pub synced: bool,
+    pub lost: bool,
+    pub original_name: Option<String>,
+    pub source: Option<String>,
+    pub kind: Option<String>,
+    pub mime: Option<String>,
+    pub last_verified_at: Option<String>,
+    pub verification: Option<String>,
+    pub labels: Vec<String>,
+}
+
+impl ShowOutput {
+    pub fn new(details: MediaDetails, labels: Vec<String>) -> Self {
+        Self {
+            media: MediaOutput::from(&details.state),
+            synced: details.state.synced,
+            lost: details.state.lost,
+            original_name: details.original_name,
+            source: details.source,
+            kind: details.kind,
+            mime: details.mime,
+            last_verified_at: details.last_verified_at,
+            verification: details.verification,
+            labels,
+        }
+    }
+}
+
+impl Display for ShowOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let unknown = "-".to_string();
+        writeln!(f, "id: {} ({})", self.media.id, self.media.file_base)?;
+        match &self.media.path {
+            Some(path) => writeln!(f, "path: {path:?}")?,
+            None => writeln!(f, "path: {unknown}")?,
+        }
+
+        writeln!(f, "hash: {}", self.media.hash)?;
+        writeln!(f, "synced: {}", self.synced)?;
+        writeln!(f, "lost: {}", self.lost)?;
+        writeln!(
+            f,
+            "original name: {}",
+            self.original_name.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(f, "source: {}", self.source.as_ref().unwrap_or(&unknown))?;
+        writeln!(
+            f,
+            "type: {} ({})",
+            self.kind.as_ref().unwrap_or(&unknown),
+            self.mime.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(
+            f,
+            "last verified: {} ({})",
+            self.last_verified_at.as_ref().unwrap_or(&unknown),
+            self.verification.as_ref().unwrap_or(&unknown)
+        )?;
+        writeln!(f, "labels: {}", self.labels.join(", "))
+    }
+}
+
+#[derive(Serialize)]
+pub struct MoveOutput {
+    pub id: i64,
+    pub file_base: String,
+    pub path: PathBuf,
+}
+
+impl Display for MoveOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "moved {} to {:?}", self.file_base, self.path)
+    }
+}
+
+#[derive(Serialize)]
+pub struct GcOutput {
+    pub dry_run: bool,
+    /// The un-synced rows which were (or, in a dry run, would be) deleted.
+    pub deleted: Vec<MediaOutput>,
+    /// The un-synced rows whose file exists (in the flush directory, or renamed in place by an adoption), and so need a closer look.
+    pub kept: Vec<MediaOutput>,
+}
+
+impl Display for GcOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let verb = if self.dry_run {
+            "would delete"
+        } else {
+            "deleted"
+        };
+
+        for media in &self.deleted {
+            writeln!(f, "{verb}: {}", media.file_base)?;
+        }
+
+        for media in &self.kept {
+            writeln!(f, "kept (its file exists): {media}")?;
+        }
+
+        writeln!(
+            f,
+            "{verb} {} un-synced rows, kept {}",
+            self.deleted.len(),
+            self.kept.len()
+        )
+    }
+}
+
+#[derive(Default, Serialize)]
+pub struct CheckOutput {
+    pub path: PathBuf,
+    pub consistent: usize,
+    pub rehashed: Vec<String>,
+    pub relocated: Vec<String>,
+    pub restored: Vec<String>,
+    pub lost: u64,
+    pub skipped: usize,
+}
+
+impl CheckOutput {
+    pub fn new(path: PathBuf, rechecks: Vec<Recheck>) -> Self {
+        let mut output = Self {
+            path,
+            ..Self::default()
+        };
+
+        for recheck in rechecks {
+            match recheck {
+                Recheck::Consistent => output.consistent += 1,
+                Recheck::Rehashed(id) => output.rehashed.push(id.file_base()),
+                Recheck::Relocated(id) => output.relocated.push(id.file_base()),
+                Recheck::Restored(id) => output.restored.push(id.file_base()),
+                Recheck::Lost(count) => output.lost += count,
+                Recheck::Skipped => output.skipped += 1,
+            }
+        }
+
+        output
+    }
+}
+
+impl Display for CheckOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for file_base in &self.rehashed {
+            writeln!(f, "rehashed: {file_base}")?;
+        }
+
+        for file_base in &self.relocated {
+            writeln!(f, "relocated: {file_base}")?;
+        }
+
+        for file_base in &self.restored {
+            writeln!(f, "restored: {file_base}")?;
+        }
+
+        writeln!(
+            f,
+            "checked {:?}: {} consistent, {} rehashed, {} relocated, {} restored, {} lost, {} skipped",
+            self.path,
+            self.consistent,
+            self.rehashed.len(),
+            self.relocated.len(),
+            self.restored.len(),
+            self.lost,
+            self.skipped
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct MigrateOutput {
+    pub applied: Vec<i32>,
+}
+
+impl Display for MigrateOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        if self.applied.is_empty() {
+            return writeln!(f, "already up to date");
+        }
+
+        for version in &self.applied {
+            writeln!(f, "applied migration {version}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+pub struct StatusOutput {
+    pub synced: i64,
+    pub unsynced: i64,
+    pub lost: i64,
+    pub verification_overdue: i64,
+    pub dead_letters: usize,
+    pub pending_migrations: Vec<i32>,
+}
+
+impl Display for StatusOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "synced: {}", self.synced)?;
+        writeln!(f, "un-synced: {}", self.unsynced)?;
+        writeln!(f, "lost: {}", self.lost)?;
+        writeln!(f, "verification overdue: {}", self.verification_overdue)?;
+        writeln!(f, "dead letters: {}", self.dead_letters)?;
+        writeln!(f, "pending migrations: {:?}", self.pending_migrations)
+    }
+}
+
+#[derive(Serialize)]
+pub struct VerifyOutput {
+    pub path: PathBuf,
+    pub safe_to_wipe: bool,
+    pub verified: usize,
+    pub missing: Vec<PathBuf>,
+    pub unreadable: Vec<PathBuf>,
+    pub skipped: Vec<PathBuf>,
+}
+
+impl VerifyOutput {
+    pub fn new(path: PathBuf, report: DeviceVerifyReport, allow_skipped: bool) -> Self {
+        Self {
+            path,
+            safe_to_wipe: report.safe_to_wipe(allow_skipped),
+            verified: report.verified,
+            missing: report.missing,
+            unreadable: report.unreadable,
+            skipped: report.skipped,
+        }
+    }
+}
+
+impl Display for VerifyOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for path in &self.missing {
+            writeln!(f, "missing: {path:?}")?;
+        }
+
+        for path in &self.unreadable {
+            writeln!(f, "unreadable: {path:?}")?;
+        }
+
+        for path in &self.skipped {
+            writeln!(f, "skipped: {path:?}")?;
+        }
+
+        let verdict = if self.safe_to_wipe {
+            "safe to wipe"
+        } else {
+            "NOT safe to wipe"
+        };
+        writeln!(
+            f,
+            "{:?} is {verdict}: {} verified, {} missing, {} unreadable, {} skipped",
+            self.path,
+            self.verified,
+            self.missing.len(),
+            self.unreadable.len(),
+            self.skipped.len()
+        )
+    }
+}
@@ -0,0 +1,63 @@
+use blarg::{CommandLineParser, Parameter, Scalar, Switch, derive::*};
+use majdool_lib::db::database::tmp_initialize;
+use majdool_lib::fs::filesystem::MediaFilesystem;
+use majdool_lib::library::check_library;
+use majdool_lib::rebuild::IndexRebuilder;
+use std::path::Path;
+use std::process::exit;
+
+#[derive(Default, BlargParser)]
+#[blarg(program = "majdool_rebuilder")]
+struct Args {
+    #[blarg(help = "Target directory path to rebuild the index from")]
+    target: String,
+    #[blarg(help = "Trust the hash cached in each file's extended attributes")]
+    hash_cache: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Args = Args::blarg_parse();
+    let target = Path::new(&args.target);
+
+    if !target.exists() || !target.is_dir() {
+        panic!("invalid target path (must exist and be a directory): {target:?}")
+    }
+
+    let target = target.canonicalize().unwrap();
+    let mut index_db = tmp_initialize().await;
+
+    // A fresh database is initialized with the target's library (by `majdool init`) before rebuilding.
+    if let Err(e) = check_library(&mut index_db, &target).await {
+        println!("{e}");
+        exit(1);
+    }
+
+    let filesystem = MediaFilesystem::new(target);
+    let mut rebuilder = IndexRebuilder::new(index_db, filesystem);
+
+    if args.hash_cache {
+        rebuilder = rebuilder.with_hash_cache();
+    }
+    let report = rebuilder
+        .rebuild()
+        .await
+        .expect("rebuild failed (the media_index must be empty)");
+
+    for path in &report.unrecognized {
+        println!("unrecognized: {path:?}");
+    }
+
+    for path in &report.unreadable {
+        println!("unreadable: {path:?}");
+    }
+
+    for path in &report.conflicts {
+        println!("conflict: {path:?}");
+    }
+
+    println!(
+        "restored {} ({} with sidecar metadata)",
+        report.restored, report.sidecars
+    );
+}
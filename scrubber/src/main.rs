@@ -0,0 +1,90 @@
+use blarg::{CommandLineParser, Parameter, Scalar, Switch, derive::*};
+use majdool_lib::db::database::tmp_initialize;
+use majdool_lib::library::check_library;
+use majdool_lib::scrub::{DEFAULT_PERIOD, MediaScrubber};
+use std::path::Path;
+use std::process::exit;
+use std::time::Duration;
+
+#[derive(BlargParser)]
+#[blarg(program = "majdool_scrubber", initializer = initial)]
+struct Args {
+    #[blarg(help = "Target directory path whose media is scrubbed")]
+    target: String,
+    #[blarg(option, help = "Seconds to wait between scrub batches")]
+    interval: u64,
+    #[blarg(option, help = "Days within which every synced file must be verified")]
+    period: u64,
+    #[blarg(help = "Scrub a single batch and exit")]
+    once: bool,
+    #[blarg(help = "Refresh the hash cached in each file's extended attributes")]
+    hash_cache: bool,
+}
+
+impl Args {
+    fn initial() -> Self {
+        Self {
+            target: String::default(),
+            interval: 60 * 60,
+            period: DEFAULT_PERIOD.as_secs() / (24 * 60 * 60),
+            once: false,
+            hash_cache: false,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Args = Args::blarg_parse();
+    let interval = Duration::from_secs(args.interval);
+    let period = Duration::from_secs(args.period * 24 * 60 * 60);
+
+    let target = Path::new(&args.target);
+
+    if !target.exists() || !target.is_dir() {
+        panic!("invalid target path (must exist and be a directory): {target:?}")
+    }
+
+    let mut index_db = tmp_initialize().await;
+
+    // Never record verifications against another library's target (see Target Initialization).
+    if let Err(e) = check_library(&mut index_db, target.canonicalize().unwrap()).await {
+        println!("{e}");
+        exit(1);
+    }
+
+    let mut scrubber = MediaScrubber::new(index_db);
+
+    if args.hash_cache {
+        scrubber = scrubber.with_hash_cache();
+    }
+
+    loop {
+        let limit = scrubber.batch_size(interval, period).await.unwrap();
+        let report = scrubber.scrub(limit).await.unwrap();
+
+        for media in &report.corrupt {
+            println!("corrupt: {} {:?}", media.id.file_base(), media.path);
+        }
+
+        for media in &report.missing {
+            println!("missing: {} {:?}", media.id.file_base(), media.path);
+        }
+
+        for media in &report.unreadable {
+            println!("unreadable: {} {:?}", media.id.file_base(), media.path);
+        }
+
+        let overdue = scrubber.overdue(period).await.unwrap();
+        println!(
+            "verified {}/{limit}, {overdue} overdue (not verified within {} days)",
+            report.verified, args.period
+        );
+
+        if args.once {
+            break;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,109 @@
+use majdool_lib::api::Retry;
+use majdool_lib::db::database::MediaIndexDatabase;
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+
+struct DeadLetter {
+    retry: Option<Retry>,
+    event: String,
+    paths: Vec<PathBuf>,
+    reason: String,
+    attempts: i32,
+}
+
+/// Records the events (and flushes) we couldn't handle in the `dead_letter` table, rather than letting them vanish.
+#[derive(Clone)]
+pub struct DeadLetterQueue {
+    tx: mpsc::UnboundedSender<DeadLetter>,
+}
+
+impl DeadLetterQueue {
+    pub fn spawn(mut index_db: MediaIndexDatabase) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<DeadLetter>();
+
+        tokio::spawn(async move {
+            while let Some(dead_letter) = rx.recv().await {
+                let result = index_db
+                    .dead_letter_insert(
+                        dead_letter.retry,
+                        &dead_letter.event,
+                        &dead_letter.paths,
+                        &dead_letter.reason,
+                        dead_letter.attempts,
+                    )
+                    .await;
+
+                match result {
+                    Ok(id) => println!("DLQ {id}: {}", dead_letter.reason),
+                    // As a last resort, at least leave a trace in the logs.
+                    Err(_) => println!(
+                        "DLQ (unrecorded): {} {:?} {}",
+                        dead_letter.event, dead_letter.paths, dead_letter.reason
+                    ),
+                }
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// A queue which only logs the dead letters (for tests, which have no database).
+    #[cfg(test)]
+    pub fn logging() -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<DeadLetter>();
+
+        tokio::spawn(async move {
+            while let Some(dead_letter) = rx.recv().await {
+                println!("DLQ: {} {:?}", dead_letter.reason, dead_letter.paths);
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// Record a dead letter which can't be retried (it's discarded once handled by hand).
+    pub fn push(&self, event: impl Into<String>, paths: Vec<PathBuf>, reason: impl Into<String>) {
+        self.send(None, event.into(), paths, reason.into(), 0);
+    }
+
+    /// Record a dead letter which `dlq retry` redoes by its `retry`.
+    pub fn push_retry(
+        &self,
+        retry: Retry,
+        event: impl Into<String>,
+        paths: Vec<PathBuf>,
+        reason: impl Into<String>,
+    ) {
+        self.send(Some(retry), event.into(), paths, reason.into(), 0);
+    }
+
+    pub fn push_attempted(
+        &self,
+        retry: Retry,
+        event: impl Into<String>,
+        paths: Vec<PathBuf>,
+        reason: impl Into<String>,
+        attempts: i32,
+    ) {
+        self.send(Some(retry), event.into(), paths, reason.into(), attempts);
+    }
+
+    fn send(
+        &self,
+        retry: Option<Retry>,
+        event: String,
+        paths: Vec<PathBuf>,
+        reason: String,
+        attempts: i32,
+    ) {
+        let dead_letter = DeadLetter {
+            retry,
+            event,
+            paths,
+            reason,
+            attempts,
+        };
+        // The recording task only stops once all the senders are gone, so this can't fail.
+        self.tx.send(dead_letter).unwrap();
+    }
+}
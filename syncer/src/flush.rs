@@ -0,0 +1,198 @@
+use crate::dlq::DeadLetterQueue;
+use majdool_lib::api::{Cleaned, Flush, Retry};
+use majdool_lib::config::{Cleanup, imported_path};
+use majdool_lib::media::MediaSystem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// Failed flushes are retried this many times (with exponential backoff), before being dead lettered.
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Where the media flushed from a source is placed, and what becomes of its source file (see the source config).
+#[derive(Debug, Default)]
+pub struct Destination {
+    /// The absolute directory which newly flushed media is moved into.
+    pub directory: Option<PathBuf>,
+    pub label: Option<String>,
+    /// The source path, which the source files are beneath.
+    pub source: PathBuf,
+    pub cleanup: Cleanup,
+    pub cleanup_dry_run: bool,
+}
+
+type Item = (PathBuf, Arc<Destination>, u32);
+
+/// A queue of source paths to flush, drained by a pool of workers.
+#[derive(Clone)]
+pub struct FlushQueue {
+    tx: mpsc::UnboundedSender<Item>,
+}
+
+/// The pool of workers draining a `FlushQueue`.
+pub struct FlushWorkers {
+    stop: watch::Sender<bool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl FlushQueue {
+    /// Spawns a worker for each of the `systems`.
+    /// The workers exit once stopped (see `FlushWorkers::stop`), or once every `FlushQueue` handle has been dropped and the queue is drained.
+    pub fn spawn(systems: Vec<MediaSystem>, dead_letters: DeadLetterQueue) -> (Self, FlushWorkers) {
+        let (tx, rx) = mpsc::unbounded_channel::<Item>();
+        let (stop, stopped) = watch::channel(false);
+        let retry_tx = tx.downgrade();
+        let rx = Arc::new(Mutex::new(rx));
+        let handles = systems
+            .into_iter()
+            .map(|mut system| {
+                let rx = rx.clone();
+                let mut stopped = stopped.clone();
+                let retry_tx = retry_tx.clone();
+                let dead_letters = dead_letters.clone();
+                tokio::spawn(async move {
+                    loop {
+                        // A stop only ever interrupts the wait for the next item (the receive is cancel safe), never a flush.
+                        let item = tokio::select! {
+                            biased;
+                            _ = stopped.wait_for(|stopped| *stopped) => break,
+                            item = async { rx.lock().await.recv().await } => item,
+                        };
+                        let Some((path, destination, attempt)) = item else {
+                            break;
+                        };
+
+                        match system.flush_file(&path).await {
+                            Ok(flush) => {
+                                match flush {
+                                    Flush::Flushed(id) => {
+                                        println!("flushed {path:?}: {}", id.file_base())
+                                    }
+                                    Flush::Duplicate(id) => {
+                                        println!("duplicate {path:?}: {}", id.file_base())
+                                    }
+                                }
+
+                                // The flush itself succeeded, so a failed placement isn't retried (the retry would find a duplicate).
+                                // Nor is the source cleaned up, since the index may no longer say where its copy is.
+                                if place(&mut system, flush, &destination).await.is_err() {
+                                    dead_letters.push(
+                                        "place",
+                                        vec![path.clone()],
+                                        format!(
+                                            "failed to place {} (the source was kept)",
+                                            flush.id().file_base()
+                                        ),
+                                    );
+                                } else if cleanup(&mut system, &path, flush, &destination)
+                                    .await
+                                    .is_err()
+                                {
+                                    dead_letters.push(
+                                        "cleanup",
+                                        vec![path],
+                                        format!(
+                                            "failed to clean up after {} (the source was kept)",
+                                            flush.id().file_base()
+                                        ),
+                                    );
+                                }
+                            }
+                            Err(_) if attempt + 1 < MAX_ATTEMPTS => {
+                                let backoff = INITIAL_BACKOFF * 2u32.pow(attempt);
+                                println!("flush failed (retrying in {backoff:?}): {path:?}");
+                                let retry_tx = retry_tx.clone();
+                                tokio::spawn(async move {
+                                    tokio::time::sleep(backoff).await;
+                                    // If the queue has already shut down, the retry is abandoned (it'll be caught up on restart).
+                                    if let Some(tx) = retry_tx.upgrade() {
+                                        let _ = tx.send((path, destination, attempt + 1));
+                                    }
+                                });
+                            }
+                            Err(_) => dead_letters.push_attempted(
+                                Retry::Flush,
+                                "flush",
+                                vec![path],
+                                format!("flush failed after {MAX_ATTEMPTS} attempts"),
+                                MAX_ATTEMPTS as i32,
+                            ),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        (Self { tx }, FlushWorkers { stop, handles })
+    }
+
+    pub fn push(&self, path: PathBuf, destination: &Arc<Destination>) {
+        // Once the workers have stopped (we're shutting down), the path is left to the catch-up on restart.
+        let _ = self.tx.send((path, destination.clone(), 0));
+    }
+}
+
+impl FlushWorkers {
+    /// Stop taking items off the queue, returning once each worker has finished its current flush.
+    /// The items still queued are abandoned - their source files are caught up on the next start.
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+
+        for handle in self.handles {
+            if let Err(e) = handle.await {
+                println!("flush worker failed: {e:?}");
+            }
+        }
+    }
+}
+
+/// Label the media, and move it into the destination directory if it's new (duplicates already have their place).
+async fn place(
+    system: &mut MediaSystem,
+    flush: Flush,
+    destination: &Destination,
+) -> Result<(), ()> {
+    if let Some(label) = &destination.label {
+        system.label_media(flush.id(), label).await?;
+    }
+
+    if let (Flush::Flushed(id), Some(directory)) = (flush, &destination.directory) {
+        let path = system.move_media(id, directory).await?;
+        println!("moved {}: {path:?}", id.file_base());
+    }
+
+    Ok(())
+}
+
+/// Clean up the source file, as per the destination's cleanup (see `MediaSystem::cleanup_source`).
+async fn cleanup(
+    system: &mut MediaSystem,
+    path: &Path,
+    flush: Flush,
+    destination: &Destination,
+) -> Result<(), ()> {
+    let cleanup = match destination.cleanup {
+        Cleanup::Keep => return Ok(()),
+        Cleanup::Delete => Cleaned::Deleted,
+        Cleanup::Move => Cleaned::Moved(imported_path(&destination.source, path).ok_or(())?),
+    };
+
+    match system
+        .cleanup_source(
+            path,
+            flush.id(),
+            cleanup.clone(),
+            destination.cleanup_dry_run,
+        )
+        .await?
+    {
+        Cleaned::DryRun => println!("would clean up {path:?}: {}", cleanup.as_str()),
+        Cleaned::Moved(imported) => println!("cleaned up {path:?}: moved to {imported:?}"),
+        cleaned => println!("cleaned up {path:?}: {}", cleaned.as_str()),
+    }
+
+    Ok(())
+}
@@ -1,77 +1,528 @@
-use notify::event::{CreateKind, ModifyKind};
-use notify::{Error, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use crate::dlq::DeadLetterQueue;
+use crate::overflow::Overflow;
+use majdool_lib::api::Retry;
+use majdool_lib::config::Overflow as OverflowPolicy;
+use majdool_lib::fs::filesystem::FlushWrites;
+use majdool_lib::fs::fsutil::walk_files;
+use notify::event::{AccessKind, AccessMode, CreateKind, ModifyKind, RenameMode};
+use notify::{Error, Event, EventHandler, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
 
-pub struct SourceListener<C: Fn(PathBuf) -> ()> {
+#[derive(Debug)]
+pub enum SourceEvent {
+    /// The file was created or written to.
+    Changed(PathBuf),
+    /// The file was closed after being written to.
+    Closed(PathBuf),
+}
+
+impl SourceEvent {
+    pub fn path(&self) -> &Path {
+        match self {
+            SourceEvent::Changed(path) | SourceEvent::Closed(path) => path,
+        }
+    }
+}
+
+/// Handles the events of a source.
+/// The listener waits for each event to be handled, so a slow handler applies backpressure (see `Overflow`).
+pub trait SourceHandler: Send + Sync {
+    fn handle(&self, event: SourceEvent) -> impl Future<Output = ()> + Send;
+}
+
+pub struct SourceListener<H: SourceHandler> {
+    queue: usize,
+    overflow: Arc<Overflow>,
+    handler: H,
+    dead_letters: DeadLetterQueue,
+}
+
+impl<H: SourceHandler> SourceListener<H> {
+    /// Up to `queue` watcher events are buffered while the handler is busy, after which they're dealt with by the `overflow` policy.
+    pub fn new(
+        handler: H,
+        queue: usize,
+        overflow: OverflowPolicy,
+        dead_letters: DeadLetterQueue,
+    ) -> Self {
+        SourceListener {
+            queue: queue.max(1),
+            overflow: Arc::new(Overflow::new(overflow)),
+            handler,
+            dead_letters,
+        }
+    }
+
+    /// Watches the source, after first catching up on the files which are already there.
+    /// The catch-up happens after the watch is established, so that nothing is missed between the two.
+    pub async fn listen(self, source: &Path) {
+        let (tx, mut rx) = mpsc::channel(self.queue);
+        let overflow = self.overflow.clone();
+        // The watcher's thread must never block on the handler (or the kernel starts dropping events instead).
+        let watched = watch(source, move |res: Result<Event, Error>| {
+            if let Ok(event) = &res
+                && event.need_rescan()
+            {
+                overflow.lost();
+                return;
+            }
+
+            match tx.try_send(res) {
+                Ok(_) => {}
+                Err(TrySendError::Full(Ok(event))) => overflow.overflowed(&event.paths),
+                Err(TrySendError::Full(Err(e))) => overflow.overflowed(&e.paths),
+                // The listener only drops the receiver when it's stopping, at which point the events are no longer wanted.
+                Err(TrySendError::Closed(_)) => {}
+            }
+        });
+        let _watcher = match watched {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                self.dead_letters.push(
+                    "error",
+                    vec![source.to_path_buf()],
+                    format!("failed to watch the source: {e}"),
+                );
+                return;
+            }
+        };
+        catch_up(source, &self.handler).await;
+
+        loop {
+            let res = match rx.try_recv() {
+                Ok(res) => res,
+                // Recover the overflow once the queue has drained, so that the watcher has room again.
+                Err(TryRecvError::Empty) if self.overflow.pending() => {
+                    self.recover(source).await;
+                    continue;
+                }
+                Err(TryRecvError::Empty) => match rx.recv().await {
+                    Some(res) => res,
+                    None => break,
+                },
+                Err(TryRecvError::Disconnected) => break,
+            };
+
+            match res {
+                Ok(event) => self.accept(event).await,
+                Err(e) => {
+                    self.dead_letters
+                        .push("error", e.paths.clone(), format!("watch error: {e}"));
+                }
+            }
+        }
+    }
+
+    async fn accept(&self, event: Event) {
+        match event.kind {
+            EventKind::Create(CreateKind::File) => match accept_single_path(event) {
+                Ok(path) => {
+                    self.handler.handle(SourceEvent::Changed(path)).await;
+                }
+                Err((event, reason)) => {
+                    dead_letter(&self.dead_letters, Retry::Flush, event, reason);
+                }
+            },
+            EventKind::Modify(ModifyKind::Data(_)) => match accept_single_path(event) {
+                Ok(path) => {
+                    self.handler.handle(SourceEvent::Changed(path)).await;
+                }
+                Err((event, reason)) => {
+                    dead_letter(&self.dead_letters, Retry::Flush, event, reason);
+                }
+            },
+            EventKind::Create(CreateKind::Folder)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+            | EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => {
+                // A directory (ex: a camera folder) may arrive with its contents already inside it.
+                match accept_single_path(event) {
+                    Ok(path) => {
+                        changed_recursive(path, &self.handler).await;
+                    }
+                    Err((event, reason)) => {
+                        dead_letter(&self.dead_letters, Retry::Flush, event, reason);
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => match accept_rename(event) {
+                Ok(path) => {
+                    changed_recursive(path, &self.handler).await;
+                }
+                Err((event, reason)) => {
+                    dead_letter(&self.dead_letters, Retry::Flush, event, reason);
+                }
+            },
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                match accept_single_path(event) {
+                    Ok(path) => {
+                        self.handler.handle(SourceEvent::Closed(path)).await;
+                    }
+                    Err((event, reason)) => {
+                        dead_letter(&self.dead_letters, Retry::Flush, event, reason);
+                    }
+                }
+            }
+            EventKind::Access(_)
+            | EventKind::Remove(_)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::From))
+            | EventKind::Modify(ModifyKind::Metadata(_)) => {
+                // The 'do nothing' events.
+                // These don't need to be DLQ'ed, because we already understand they don't have an applicable handling response from our listener.
+            }
+            _ => {
+                dead_letter(&self.dead_letters, Retry::Flush, event, "unhandled event");
+            }
+        }
+    }
+
+    /// Catch up on the events which overflowed the queue.
+    async fn recover(&self, source: &Path) {
+        let recovery = self.overflow.recover();
+
+        if recovery.rescan {
+            println!("recovering from overflow: rescanning {source:?}");
+            catch_up(source, &self.handler).await;
+        } else {
+            println!(
+                "recovering from overflow: {} spilled events",
+                recovery.paths.len()
+            );
+
+            for path in recovery.paths {
+                changed_recursive(path, &self.handler).await;
+            }
+        }
+    }
+}
+
+/// Listens for unsanctioned changes to the target, since we can't assume exclusive write access.
+pub struct TargetListener<C: Fn(PathBuf)> {
     tx: mpsc::Sender<Result<Event, Error>>,
     rx: mpsc::Receiver<Result<Event, Error>>,
     callback: C,
+    flush_writes: FlushWrites,
+    dead_letters: DeadLetterQueue,
 }
 
-impl<C: Fn(PathBuf) -> ()> SourceListener<C> {
-    pub fn new(callback: C) -> Self {
+impl<C: Fn(PathBuf)> TargetListener<C> {
+    pub fn new(callback: C, dead_letters: DeadLetterQueue) -> Self {
         let (tx, rx) = mpsc::channel(100);
 
-        SourceListener { tx, rx, callback }
+        TargetListener {
+            tx,
+            rx,
+            callback,
+            flush_writes: FlushWrites::default(),
+            dead_letters,
+        }
     }
-}
 
-impl<C: Fn(PathBuf) -> () + Send> SourceListener<C> {
-    pub async fn listen(mut self, source: &Path) {
-        let mut watcher = RecommendedWatcher::new(
-            move |res| {
-                self.tx.blocking_send(res).unwrap();
-            },
-            notify::Config::default(),
-        )
-        .unwrap();
+    /// Ignore the writes of our own flushes, as registered in the `flush_writes` (the index already knows about them).
+    pub fn with_flush_writes(mut self, flush_writes: FlushWrites) -> Self {
+        self.flush_writes = flush_writes;
+        self
+    }
+}
 
-        watcher.watch(source, RecursiveMode::Recursive).unwrap();
+impl<C: Fn(PathBuf) + Send> TargetListener<C> {
+    pub async fn listen(mut self, target: &Path) {
+        let tx = self.tx.clone();
+        let watched = watch(target, move |res: Result<Event, Error>| {
+            // The listener only drops the receiver when it's stopping, at which point the events are no longer wanted.
+            let _ = tx.blocking_send(res);
+        });
+        let _watcher = match watched {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                self.dead_letters.push(
+                    "error",
+                    vec![target.to_path_buf()],
+                    format!("failed to watch the target: {e}"),
+                );
+                return;
+            }
+        };
 
         while let Some(res) = self.rx.recv().await {
             match res {
-                Ok(event) => {
-                    match event.kind {
-                        EventKind::Create(CreateKind::File) => match accept_single_path(event) {
-                            Ok(path) => {
-                                (self.callback)(path);
-                            }
-                            Err(reason) => {
-                                println!("DLQ: {reason}")
-                            }
-                        },
-                        EventKind::Modify(ModifyKind::Data(_)) => match accept_single_path(event) {
-                            Ok(path) => {
-                                (self.callback)(path);
-                            }
-                            Err(reason) => {
-                                println!("DLQ: {reason}")
-                            }
-                        },
-                        EventKind::Access(_)
-                        | EventKind::Remove(_)
-                        | EventKind::Modify(ModifyKind::Metadata(_)) => {
-                            // The 'do nothing' events.
-                            // These don't need to be DLQ'ed, because we already understand they don't have an applicable handling response from our listener.
-                        }
-                        _ => {
-                            println!("DLQ: unhandled event: {:?}", event)
-                        }
+                Ok(event) => self.accept(event),
+                Err(e) => {
+                    self.dead_letters
+                        .push("error", e.paths.clone(), format!("watch error: {e}"));
+                }
+            }
+        }
+    }
+
+    fn accept(&self, event: Event) {
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(_)) | EventKind::Remove(_) => {
+                // Renames report both the 'from' and 'to' paths - each needs to be rechecked.
+                for path in event.paths {
+                    (self.callback)(path);
+                }
+            }
+            EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                // A file is only rechecked once it's completely written, rather than for each of its writes.
+                for path in event.paths {
+                    if !self.flush_writes.take(&path) {
+                        (self.callback)(path);
                     }
                 }
-                Err(e) => println!("error: {:?}", e),
+            }
+            EventKind::Access(_) | EventKind::Create(_) | EventKind::Modify(_) => {
+                // The 'do nothing' events.
+                // Creates are (almost always) our own flushes, and writes are rechecked once the file is closed.
+            }
+            _ => {
+                dead_letter(
+                    &self.dead_letters,
+                    Retry::Recheck,
+                    event,
+                    "unhandled target event",
+                );
             }
         }
     }
 }
 
-fn accept_single_path<'a>(mut event: Event) -> Result<PathBuf, String> {
+/// Reports every file under the source as changed (ex: files which arrived while we weren't watching).
+pub async fn catch_up(source: &Path, handler: &impl SourceHandler) {
+    match walk_files(source).await {
+        Ok(paths) => {
+            println!("catch-up: {} files in {source:?}", paths.len());
+
+            for path in paths {
+                handler.handle(SourceEvent::Changed(path)).await;
+            }
+        }
+        Err(e) => println!("catch-up failed: {e:?}"),
+    }
+}
+
+/// Reports the path as changed, or every file beneath it if it's a directory.
+async fn changed_recursive(path: PathBuf, handler: &impl SourceHandler) {
+    if path.is_dir() {
+        catch_up(&path, handler).await;
+    } else if path.exists() {
+        // Renames on some platforms are ambiguous about the direction ('from' or 'to'), so only existing paths are reported.
+        handler.handle(SourceEvent::Changed(path)).await;
+    }
+}
+
+fn watch(path: &Path, handler: impl EventHandler) -> Result<RecommendedWatcher, Error> {
+    let mut watcher = RecommendedWatcher::new(handler, notify::Config::default())?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+fn accept_single_path(mut event: Event) -> Result<PathBuf, (Event, &'static str)> {
     // Right now, I'm not understanding what kinds of events could have multiple paths.
-    // Let's just accept 1-path events, and "DLQ" the rest.
+    // Let's just accept 1-path events, and DLQ the rest.
     match event.paths.len() {
         1 => Ok(event.paths.remove(0)),
-        0 => Err(format!("no-path create event: {event:?}")),
-        _ => Err(format!("multiple-path create event: {event:?}")),
+        0 => Err((event, "no-path event")),
+        _ => Err((event, "multiple-path event")),
+    }
+}
+
+fn accept_rename(mut event: Event) -> Result<PathBuf, (Event, &'static str)> {
+    // Renames within the source are reported as [from, to] - we only need to flush the 'to'.
+    match event.paths.len() {
+        2 => Ok(event.paths.remove(1)),
+        _ => Err((event, "malformed rename event")),
+    }
+}
+
+/// Dead letter the `event`, which `dlq retry` redoes by its `retry` (flushing the source's paths, or rechecking the target's).
+fn dead_letter(dead_letters: &DeadLetterQueue, retry: Retry, event: Event, reason: &str) {
+    dead_letters.push_retry(retry, format!("{:?}", event.kind), event.paths, reason);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{DataChange, RemoveKind};
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    /// A burst of 10k new files, which overflows the listener's queue (and likely the kernel's, too).
+    const BURST: usize = 10_000;
+
+    /// Records the reported paths - deliberately slowly, so that the listener's queue overflows in the bursts.
+    #[derive(Clone, Default)]
+    struct SlowHandler {
+        changed: Arc<Mutex<HashSet<PathBuf>>>,
+    }
+
+    impl SourceHandler for SlowHandler {
+        async fn handle(&self, event: SourceEvent) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            self.changed
+                .lock()
+                .unwrap()
+                .insert(event.path().to_path_buf());
+        }
+    }
+
+    fn listener(handler: &SlowHandler) -> SourceListener<SlowHandler> {
+        SourceListener::new(
+            handler.clone(),
+            8,
+            OverflowPolicy::Spill,
+            DeadLetterQueue::logging(),
+        )
+    }
+
+    fn changed(handler: &SlowHandler) -> HashSet<PathBuf> {
+        handler.changed.lock().unwrap().clone()
+    }
+
+    async fn burst(overflow: OverflowPolicy) {
+        let dir = tempdir().unwrap();
+        let source = dir.path().canonicalize().unwrap();
+        let handler = SlowHandler::default();
+        let listener =
+            SourceListener::new(handler.clone(), 8, overflow, DeadLetterQueue::logging());
+        let listen = {
+            let source = source.clone();
+            tokio::spawn(async move { listener.listen(&source).await })
+        };
+        // Let the watch be established.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let expected: HashSet<PathBuf> = (0..BURST)
+            .map(|i| source.join(format!("IMG_{i:04}.jpg")))
+            .collect();
+
+        for path in &expected {
+            std::fs::write(path, b"hello").unwrap();
+        }
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(120);
+
+        while !handler.changed.lock().unwrap().is_superset(&expected) {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "lost events: {} of {BURST} files reported",
+                handler
+                    .changed
+                    .lock()
+                    .unwrap()
+                    .intersection(&expected)
+                    .count()
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        listen.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn burst_spill() {
+        burst(OverflowPolicy::Spill).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn burst_rescan() {
+        burst(OverflowPolicy::Rescan).await;
+    }
+
+    #[tokio::test]
+    async fn rename_to() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("IMG_0001.jpg");
+        std::fs::write(&path, b"hello").unwrap();
+        let handler = SlowHandler::default();
+        let listener = listener(&handler);
+
+        let event =
+            Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::To))).add_path(path.clone());
+        listener.accept(event).await;
+        assert_eq!(changed(&handler), HashSet::from([path]));
+
+        // A 'to' which no longer exists (or was really a 'from') isn't reported.
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Any)))
+            .add_path(dir.path().join("IMG_0002.jpg"));
+        listener.accept(event).await;
+        assert_eq!(changed(&handler).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rename_both() {
+        let dir = tempdir().unwrap();
+        let from = dir.path().join("upload.tmp");
+        let to = dir.path().join("IMG_0001.jpg");
+        std::fs::write(&to, b"hello").unwrap();
+        let handler = SlowHandler::default();
+        let listener = listener(&handler);
+
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(from.clone())
+            .add_path(to.clone());
+        listener.accept(event).await;
+        assert_eq!(changed(&handler), HashSet::from([to.clone()]));
+
+        // A rename without both of its paths is dead lettered instead.
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both))).add_path(to);
+        listener.accept(event).await;
+        assert_eq!(changed(&handler).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn create_folder() {
+        let dir = tempdir().unwrap();
+        let folder = dir.path().join("DCIM/100CANON");
+        std::fs::create_dir_all(folder.join("nested")).unwrap();
+        let expected = HashSet::from([
+            folder.join("IMG_0001.jpg"),
+            folder.join("IMG_0002.jpg"),
+            folder.join("nested/IMG_0003.jpg"),
+        ]);
+        for path in &expected {
+            std::fs::write(path, b"hello").unwrap();
+        }
+        let handler = SlowHandler::default();
+        let listener = listener(&handler);
+
+        // A folder moved in (or created with its contents) reports each file beneath it.
+        let event =
+            Event::new(EventKind::Create(CreateKind::Folder)).add_path(dir.path().join("DCIM"));
+        listener.accept(event).await;
+        assert_eq!(changed(&handler), expected);
+    }
+
+    #[tokio::test]
+    async fn target_rechecks() {
+        let rechecked = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let rechecked = rechecked.clone();
+            TargetListener::new(
+                move |path| rechecked.lock().unwrap().push(path),
+                DeadLetterQueue::logging(),
+            )
+        };
+        let path = PathBuf::from("/target/flush/000000000000002a.jpg");
+
+        // Each write of a file being copied is ignored, until the file is closed.
+        for _ in 0..3 {
+            listener.accept(
+                Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+                    .add_path(path.clone()),
+            );
+        }
+        assert!(rechecked.lock().unwrap().is_empty());
+
+        listener.accept(
+            Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)))
+                .add_path(path.clone()),
+        );
+        listener.accept(Event::new(EventKind::Remove(RemoveKind::File)).add_path(path.clone()));
+        assert_eq!(*rechecked.lock().unwrap(), vec![path.clone(), path]);
     }
 }
@@ -1,39 +1,329 @@
+mod dlq;
+mod flush;
 mod listen;
-use listen::SourceListener;
+mod overflow;
+mod poll;
+mod recheck;
+mod removable;
+mod stable;
+use dlq::DeadLetterQueue;
+use flush::{Destination, FlushQueue};
+use listen::{SourceEvent, SourceHandler, SourceListener, TargetListener, catch_up};
+use poll::SourcePoller;
+use recheck::RecheckQueue;
+use removable::RemovableWatcher;
+use stable::StabilityDetector;
 
 use blarg::{CommandLineParser, Parameter, Scalar, derive::*};
-use majdool_lib::db::database::tmp_initialize;
-use std::path::Path;
+use majdool_lib::config::{Config, SourceConfig, Watcher};
+use majdool_lib::db::database::{MediaIndexDatabase, connect};
+use majdool_lib::fs::filesystem::FlushWrites;
+use majdool_lib::library::check_library;
+use majdool_lib::lock::HashLocks;
+use majdool_lib::media::MediaSystem;
+use majdool_lib::monitor::ConsistencyMonitor;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 
 #[derive(Default, BlargParser)]
 #[blarg(program = "majdool_syncer")]
 struct Args {
-    #[blarg(help = "Source directory path to sync from")]
-    source: String,
-    #[blarg(help = "Target directory path to sync to")]
-    target: String,
+    #[blarg(help = "Path to the syncer config (TOML), declaring the target and its sources")]
+    config: String,
 }
 
 #[tokio::main]
 async fn main() {
     let args: Args = Args::blarg_parse();
-    let source = Path::new(&args.source);
-    let target = Path::new(&args.target);
+    let mut config = match load_config(&args.config).await {
+        Ok(config) => config,
+        Err(e) => {
+            println!("invalid config: {e}");
+            exit(1);
+        }
+    };
+    let target = config.target.clone();
 
-    if !source.exists() || !source.is_dir() {
-        panic!("invalid source path (must exist and be a directory): {source:?}")
+    // Never sync into a target which the database doesn't index (see Target Initialization).
+    if let Err(e) = check_library(&mut index_db(&config.database).await, &target).await {
+        println!("{e}");
+        exit(1);
     }
 
-    if !target.exists() || !target.is_dir() {
-        panic!("invalid target path (must exist and be a directory): {target:?}")
+    let (Ok(mut terminate), Ok(mut interrupt), Ok(mut hangup)) = (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::hangup()),
+    ) else {
+        println!("failed to install the signal handlers");
+        exit(1);
+    };
+
+    let dead_letters = DeadLetterQueue::spawn(index_db(&config.database).await);
+    let mut systems = Vec::default();
+    // Shared by every flush (the workers', and the drive imports'), so that identical files are only flushed once.
+    let hash_locks = HashLocks::default();
+    // Likewise, so that the target listener can tell every flush's copies from unsanctioned writes.
+    let flush_writes = FlushWrites::default();
+    let filesystem = config
+        .filesystem(&target)
+        .with_flush_writes(flush_writes.clone());
+
+    for _ in 0..config.workers.max(1) {
+        systems.push(
+            MediaSystem::new(index_db(&config.database).await, filesystem.clone())
+                .with_hash_locks(hash_locks.clone()),
+        );
     }
 
-    let media_db = tmp_initialize().await;
+    let (flush_queue, workers) = FlushQueue::spawn(systems, dead_letters.clone());
+
+    let monitor = ConsistencyMonitor::new(index_db(&config.database).await).with_hash_cache();
+    let recheck_queue = RecheckQueue::spawn(monitor, dead_letters.clone());
 
-    let source_listener = SourceListener::new(|path| {
-        println!("callback: {path:?}");
+    let (stop_tx, stop_rx) = watch::channel(());
+    let removable = config.mounts.clone().map(|mount_root| {
+        let mut removable_watcher = RemovableWatcher::new(
+            mount_root,
+            config.database.clone(),
+            filesystem.clone(),
+            dead_letters.clone(),
+        )
+        .with_filter(config.filter.clone())
+        .with_sources(config.sources.iter().map(|source| source.path.clone()))
+        .with_hash_locks(hash_locks.clone());
+
+        if config.receipts {
+            removable_watcher = removable_watcher.with_receipts();
+        }
+
+        tokio::spawn(removable_watcher.watch(stop_rx))
     });
-    source_listener.listen(source).await;
+
+    let mut sources = spawn_sources(&config, &flush_queue, &dead_letters);
+
+    let target_listener =
+        TargetListener::new(move |path| recheck_queue.push(path), dead_letters.clone())
+            .with_flush_writes(flush_writes);
+    let target_listener = {
+        let target = target.clone();
+        tokio::spawn(async move { target_listener.listen(&target).await })
+    };
+
+    loop {
+        tokio::select! {
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = hangup.recv() => match load_config(&args.config).await {
+                // The workers, the drive watcher and the target listener were all set up with these, so the reload can't apply them.
+                Ok(reloaded)
+                    if reloaded.target != config.target
+                        || reloaded.database != config.database
+                        || reloaded.layout != config.layout
+                        || reloaded.sidecars != config.sidecars
+                        || reloaded.hash_cache != config.hash_cache
+                        || reloaded.workers != config.workers
+                        || reloaded.mounts != config.mounts
+                        || reloaded.receipts != config.receipts =>
+                {
+                    println!("rejected the reloaded config (keeping the current one): changes to the database, target, layout, sidecars, hash cache, workers, mounts and receipts need a restart");
+                }
+                Ok(reloaded) => {
+                    // The new sources start with a catch-up, so nothing pending in the old ones is missed.
+                    for source in sources.drain(..) {
+                        source.abort();
+                    }
+
+                    sources = spawn_sources(&reloaded, &flush_queue, &dead_letters);
+                    config = reloaded;
+                    println!("reloaded config: {} sources", sources.len());
+                }
+                Err(e) => println!("failed to reload config (keeping the current one): {e}"),
+            },
+        }
+    }
+
+    // Stop accepting events, then let the in-flight flushes finish (the queued ones are caught up on restart).
+    println!("shutting down");
+    for source in sources {
+        source.abort();
+    }
+
+    target_listener.abort();
+    let _ = stop_tx.send(());
+    drop(flush_queue);
+
+    let drain = async {
+        workers.stop().await;
+
+        if let Some(removable) = removable {
+            let _ = removable.await;
+        }
+    };
+
+    // An interrupted flush leaves an un-synced row (see File Flush Procedure), and its source is caught up on restart.
+    if tokio::time::timeout(Duration::from_secs(config.shutdown_timeout), drain)
+        .await
+        .is_err()
+    {
+        println!("abandoning the in-flight flushes after the shutdown timeout");
+        exit(1);
+    }
 
     println!("Doners!");
 }
+
+/// Load the config, checking that its target and sources exist (as absolute paths, which is what the index records).
+async fn load_config(path: impl AsRef<Path>) -> Result<Config, String> {
+    let mut config = Config::load(path).await?;
+    config.target = existing_directory(&config.target, "target")?;
+
+    if let Some(mount_root) = &config.mounts {
+        config.mounts = Some(existing_directory(mount_root, "mount root")?);
+    }
+
+    for source in &mut config.sources {
+        source.path = existing_directory(&source.path, "source")?;
+    }
+
+    Ok(config)
+}
+
+async fn index_db(database: &str) -> MediaIndexDatabase {
+    match connect(database).await {
+        Ok(index_db) => index_db,
+        Err(_) => {
+            println!("failed to connect to the database: {database}");
+            exit(1);
+        }
+    }
+}
+
+fn existing_directory(path: &Path, name: &str) -> Result<PathBuf, String> {
+    match path.canonicalize() {
+        Ok(path) if path.is_dir() => Ok(path),
+        _ => Err(format!(
+            "invalid {name} path (must exist and be a directory): {path:?}"
+        )),
+    }
+}
+
+fn spawn_sources(
+    config: &Config,
+    flush_queue: &FlushQueue,
+    dead_letters: &DeadLetterQueue,
+) -> Vec<JoinHandle<()>> {
+    config
+        .sources
+        .iter()
+        .map(|source| {
+            let destination = Destination {
+                directory: source.directory.as_ref().map(|d| config.target.join(d)),
+                label: source.label.clone(),
+                source: source.path.clone(),
+                cleanup: source.cleanup,
+                cleanup_dry_run: source.cleanup_dry_run,
+            };
+            tokio::spawn(watch_source(
+                source.clone(),
+                destination,
+                flush_queue.clone(),
+                dead_letters.clone(),
+            ))
+        })
+        .collect()
+}
+
+/// Watches the source with its configured watcher, passing each of its completed (and not ignored) files to the flush queue.
+/// Aborting this stops the source's intake entirely (its pending files are caught up on the next start).
+async fn watch_source(
+    source: SourceConfig,
+    destination: Destination,
+    flush_queue: FlushQueue,
+    dead_letters: DeadLetterQueue,
+) {
+    let source = Arc::new(source);
+    let destination = Arc::new(destination);
+    let stability_detector = StabilityDetector::new(Duration::from_secs(source.quiet));
+    let stable_source = source.clone();
+    // The files which the filter (or the ignore patterns) turned away, since the syncer started.
+    let skipped = Arc::new(AtomicUsize::new(0));
+    let stable_tx = stability_detector.spawn(source.queue, move |path| {
+        let source = stable_source.clone();
+        let flush_queue = flush_queue.clone();
+        let destination = destination.clone();
+        let skipped = skipped.clone();
+
+        async move {
+            // Files are only judged once they're stable, so that each is counted once (not on each of its events).
+            // The size is only final then, too (a file being written starts out empty).
+            // A file which can't be stat-ed is still pushed, so that its flush fails loudly.
+            match tokio::fs::metadata(&path).await {
+                Ok(metadata) if !source.accepts(&path, metadata.len()) => {
+                    let skipped = skipped.fetch_add(1, Ordering::Relaxed) + 1;
+                    println!(
+                        "skipped {path:?} ({skipped} skipped from {:?})",
+                        source.path
+                    );
+                }
+                _ => flush_queue.push(path, &destination),
+            }
+        }
+    });
+    let intake = Intake {
+        source: source.clone(),
+        stable_tx,
+    };
+
+    let rescan = async {
+        if source.rescan > 0 {
+            loop {
+                tokio::time::sleep(Duration::from_secs(source.rescan)).await;
+                catch_up(&source.path, &intake).await;
+            }
+        }
+    };
+
+    let watch = async {
+        match source.watcher {
+            Watcher::Notify => {
+                let source_listener = SourceListener::new(
+                    intake.clone(),
+                    source.queue,
+                    source.overflow,
+                    dead_letters,
+                );
+                source_listener.listen(&source.path).await;
+            }
+            Watcher::Poll => {
+                let source_poller =
+                    SourcePoller::new(Duration::from_secs(source.poll.max(1)), intake.clone());
+                source_poller.listen(&source.path).await;
+            }
+        }
+    };
+
+    tokio::join!(rescan, watch);
+}
+
+/// Passes the source's events to the stability detector, minus those for the already imported files.
+#[derive(Clone)]
+struct Intake {
+    source: Arc<SourceConfig>,
+    stable_tx: mpsc::Sender<SourceEvent>,
+}
+
+impl SourceHandler for Intake {
+    async fn handle(&self, event: SourceEvent) {
+        if !self.source.imported(event.path()) {
+            // The detector only stops once the source does.
+            let _ = self.stable_tx.send(event).await;
+        }
+    }
+}
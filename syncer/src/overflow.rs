@@ -0,0 +1,210 @@
+use majdool_lib::config::Overflow as OverflowPolicy;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+static SPILLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Keeps track of the events which didn't fit in the listener's queue, so that they can be recovered once it drains.
+pub struct Overflow {
+    policy: OverflowPolicy,
+    rescan: AtomicBool,
+    spill: Mutex<Spill>,
+}
+
+struct Spill {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+/// What the listener needs to catch up on after an overflow.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Recovery {
+    /// The whole source needs to be rescanned.
+    pub rescan: bool,
+    /// The paths of the spilled events.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Overflow {
+    pub fn new(policy: OverflowPolicy) -> Self {
+        let spill = std::env::temp_dir().join(format!(
+            "majdool-{}-{}.spill",
+            std::process::id(),
+            SPILLS.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        Self {
+            policy,
+            rescan: AtomicBool::new(false),
+            spill: Mutex::new(Spill {
+                path: spill,
+                file: None,
+            }),
+        }
+    }
+
+    /// Note the paths of an event which didn't fit in the queue.
+    /// This is called from the watcher's thread, so it blocks (briefly) rather than awaiting.
+    pub fn overflowed(&self, paths: &[PathBuf]) {
+        if self.policy == OverflowPolicy::Spill && self.spill_paths(paths).is_ok() {
+            return;
+        }
+
+        // If the spill fails (ex: the disk is full), a rescan still recovers everything.
+        self.rescan.store(true, Ordering::Release);
+    }
+
+    /// Note that the watcher itself lost events (ex: the kernel's inotify queue overflowed).
+    pub fn lost(&self) {
+        self.rescan.store(true, Ordering::Release);
+    }
+
+    pub fn pending(&self) -> bool {
+        self.rescan.load(Ordering::Acquire)
+            || self.spill.lock().is_ok_and(|spill| spill.file.is_some())
+    }
+
+    /// Take everything which has overflowed so far.
+    pub fn recover(&self) -> Recovery {
+        let mut recovery = Recovery {
+            rescan: self.rescan.swap(false, Ordering::AcqRel),
+            paths: Vec::default(),
+        };
+
+        match self.take_spill() {
+            Ok(paths) => recovery.paths = paths,
+            Err(e) => {
+                println!("failed to read the spill: {e:?}");
+                recovery.rescan = true;
+            }
+        }
+
+        recovery
+    }
+
+    fn spill_paths(&self, paths: &[PathBuf]) -> Result<(), std::io::Error> {
+        let mut spill = self
+            .spill
+            .lock()
+            .map_err(|_| std::io::Error::other("poisoned spill"))?;
+        let mut file = match spill.file.take() {
+            Some(file) => file,
+            None => OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .read(true)
+                .write(true)
+                .open(&spill.path)?,
+        };
+
+        // Paths can contain newlines, but never NULs.
+        let mut bytes = Vec::default();
+
+        for path in paths {
+            bytes.extend_from_slice(path.as_os_str().as_bytes());
+            bytes.push(0);
+        }
+
+        let result = file.write_all(&bytes);
+        spill.file = Some(file);
+        result
+    }
+
+    fn take_spill(&self) -> Result<Vec<PathBuf>, std::io::Error> {
+        let mut spill = self
+            .spill
+            .lock()
+            .map_err(|_| std::io::Error::other("poisoned spill"))?;
+        let Some(mut file) = spill.file.take() else {
+            return Ok(Vec::default());
+        };
+
+        let mut bytes = Vec::default();
+        file.rewind()?;
+        file.read_to_end(&mut bytes)?;
+        std::fs::remove_file(&spill.path)?;
+
+        // A burst usually spills several events per path (ex: create, modify and close).
+        let mut seen = HashSet::new();
+        Ok(bytes
+            .split(|byte| *byte == 0)
+            .filter(|path| !path.is_empty() && seen.insert(*path))
+            .map(|path| PathBuf::from(OsString::from_vec(path.to_vec())))
+            .collect())
+    }
+}
+
+impl Drop for Overflow {
+    fn drop(&mut self) {
+        if let Ok(spill) = self.spill.get_mut()
+            && spill.file.is_some()
+        {
+            let _ = std::fs::remove_file(&spill.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spill_and_recover() {
+        let overflow = Overflow::new(OverflowPolicy::Spill);
+        assert!(!overflow.pending());
+
+        overflow.overflowed(&[PathBuf::from("/source/a.jpg")]);
+        overflow.overflowed(&[
+            PathBuf::from("/source/b\nc.jpg"),
+            PathBuf::from("/source/d.jpg"),
+        ]);
+        overflow.overflowed(&[PathBuf::from("/source/a.jpg")]);
+        assert!(overflow.pending());
+
+        assert_eq!(
+            overflow.recover(),
+            Recovery {
+                rescan: false,
+                paths: vec![
+                    PathBuf::from("/source/a.jpg"),
+                    PathBuf::from("/source/b\nc.jpg"),
+                    PathBuf::from("/source/d.jpg"),
+                ],
+            }
+        );
+        assert!(!overflow.pending());
+        assert_eq!(overflow.recover(), Recovery::default());
+    }
+
+    #[test]
+    fn rescan_and_recover() {
+        let overflow = Overflow::new(OverflowPolicy::Rescan);
+
+        overflow.overflowed(&[PathBuf::from("/source/a.jpg")]);
+        assert!(overflow.pending());
+
+        assert_eq!(
+            overflow.recover(),
+            Recovery {
+                rescan: true,
+                paths: Vec::default(),
+            }
+        );
+        assert!(!overflow.pending());
+    }
+
+    #[test]
+    fn lost_and_recover() {
+        let overflow = Overflow::new(OverflowPolicy::Spill);
+
+        overflow.lost();
+
+        assert!(overflow.recover().rescan);
+    }
+}
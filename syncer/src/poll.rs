@@ -0,0 +1,115 @@
+use crate::listen::{SourceEvent, SourceHandler};
+use majdool_lib::fs::fsutil::walk_files;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+type Snapshot = HashMap<PathBuf, (u64, SystemTime)>;
+
+/// Watches the source by periodically rescanning it, for filesystems which don't deliver notify events (ex: NFS/SMB mounts).
+pub struct SourcePoller<H: SourceHandler> {
+    interval: Duration,
+    handler: H,
+}
+
+impl<H: SourceHandler> SourcePoller<H> {
+    pub fn new(interval: Duration, handler: H) -> Self {
+        Self { interval, handler }
+    }
+
+    /// The first scan reports every file, which doubles as the catch-up.
+    pub async fn listen(self, source: &Path) {
+        let mut previous = Snapshot::default();
+        let mut interval = tokio::time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+            let next = match snapshot(source).await {
+                Ok(next) => next,
+                Err(e) => {
+                    // The mount may be temporarily unavailable - keep the previous snapshot, so nothing is reported twice.
+                    println!("poll failed: {e:?}");
+                    continue;
+                }
+            };
+
+            for path in changes(&previous, &next) {
+                self.handler.handle(SourceEvent::Changed(path)).await;
+            }
+
+            previous = next;
+        }
+    }
+}
+
+async fn snapshot(source: &Path) -> Result<Snapshot, std::io::Error> {
+    let mut snapshot = Snapshot::default();
+
+    for path in walk_files(source).await? {
+        // Files may disappear between the walk and the stat.
+        if let Ok(metadata) = tokio::fs::metadata(&path).await
+            && let Ok(modified) = metadata.modified()
+        {
+            snapshot.insert(path, (metadata.len(), modified));
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// The paths which are new or changed (by size or mtime) in the `next` snapshot, in sorted order.
+fn changes(previous: &Snapshot, next: &Snapshot) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = next
+        .iter()
+        .filter(|(path, stat)| previous.get(*path) != Some(*stat))
+        .map(|(path, _)| path.clone())
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn changes_new_and_modified() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+        let previous = Snapshot::from([
+            (PathBuf::from("a"), (1, t0)),
+            (PathBuf::from("b"), (1, t0)),
+            (PathBuf::from("c"), (1, t0)),
+            (PathBuf::from("gone"), (1, t0)),
+        ]);
+        let next = Snapshot::from([
+            (PathBuf::from("a"), (1, t0)),
+            (PathBuf::from("b"), (2, t0)),
+            (PathBuf::from("c"), (1, t1)),
+            (PathBuf::from("d"), (1, t0)),
+        ]);
+
+        assert_eq!(
+            changes(&previous, &next),
+            vec![PathBuf::from("b"), PathBuf::from("c"), PathBuf::from("d")]
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_files() {
+        let dir = tempdir().unwrap();
+        tokio::fs::create_dir(dir.path().join("DCIM"))
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("DCIM/a.jpg"), b"hello")
+            .await
+            .unwrap();
+
+        let snapshot = snapshot(dir.path()).await.unwrap();
+
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[&dir.path().join("DCIM/a.jpg")].0, 5);
+        assert!(changes(&snapshot, &snapshot).is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::dlq::DeadLetterQueue;
+use majdool_lib::api::Retry;
+use majdool_lib::monitor::ConsistencyMonitor;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// At most this many target paths wait for a recheck - beyond that, they're dead lettered (for `dlq retry`).
+const CAPACITY: usize = 1024;
+
+/// A queue of target paths for the consistency monitor to recheck, one at a time.
+/// A path which is already waiting isn't queued again, since its recheck will see the latest state anyway.
+#[derive(Clone)]
+pub struct RecheckQueue {
+    tx: mpsc::Sender<PathBuf>,
+    pending: Arc<Mutex<HashSet<PathBuf>>>,
+    dead_letters: DeadLetterQueue,
+}
+
+impl RecheckQueue {
+    /// The monitor exits once every `RecheckQueue` handle has been dropped and the queue is drained.
+    pub fn spawn(mut monitor: ConsistencyMonitor, dead_letters: DeadLetterQueue) -> Self {
+        let (tx, mut rx) = mpsc::channel::<PathBuf>(CAPACITY);
+        let pending = Arc::new(Mutex::new(HashSet::new()));
+        let queue = Self {
+            tx,
+            pending: pending.clone(),
+            dead_letters: dead_letters.clone(),
+        };
+
+        tokio::spawn(async move {
+            while let Some(path) = rx.recv().await {
+                // Any change from here on needs another recheck, so the path can be queued again.
+                pending.lock().unwrap().remove(&path);
+
+                match monitor.recheck(&path).await {
+                    Ok(rechecks) => println!("recheck {path:?}: {rechecks:?}"),
+                    Err(_) => dead_letters.push_retry(
+                        Retry::Recheck,
+                        "recheck",
+                        vec![path],
+                        "recheck failed",
+                    ),
+                }
+            }
+        });
+
+        queue
+    }
+
+    pub fn push(&self, path: PathBuf) {
+        if !self.pending.lock().unwrap().insert(path.clone()) {
+            return;
+        }
+
+        match self.tx.try_send(path) {
+            Ok(_) => {}
+            Err(TrySendError::Full(path)) => {
+                self.pending.lock().unwrap().remove(&path);
+                self.dead_letters.push_retry(
+                    Retry::Recheck,
+                    "recheck",
+                    vec![path],
+                    "the recheck queue is full",
+                );
+            }
+            // The monitor only stops when we're exiting.
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
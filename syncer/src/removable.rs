@@ -0,0 +1,205 @@
+use crate::dlq::DeadLetterQueue;
+use majdool_lib::api::Retry;
+use majdool_lib::db::database::connect;
+use majdool_lib::device::{Volume, mounted_volumes};
+use majdool_lib::filter::Filter;
+use majdool_lib::fs::filesystem::MediaFilesystem;
+use majdool_lib::fs::receipt::{already_imported, run_id, write_receipt};
+use majdool_lib::lock::HashLocks;
+use majdool_lib::media::MediaSystem;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Mounts are discovered by polling, since there's no notify event for a volume appearing under the mount root.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watches a mount root (ex: `/media`) for removable drives, running a drive flush of each one as it's mounted.
+pub struct RemovableWatcher {
+    mount_root: PathBuf,
+    database: String,
+    filesystem: MediaFilesystem,
+    receipts: bool,
+    filter: Filter,
+    hash_locks: HashLocks,
+    /// The target and the sources, whose devices are never imported (even when they're mounted beneath the mount root).
+    exclude: Vec<PathBuf>,
+    dead_letters: DeadLetterQueue,
+}
+
+impl RemovableWatcher {
+    pub fn new(
+        mount_root: PathBuf,
+        database: String,
+        filesystem: MediaFilesystem,
+        dead_letters: DeadLetterQueue,
+    ) -> Self {
+        Self {
+            mount_root,
+            database,
+            exclude: vec![filesystem.root().to_path_buf()],
+            filesystem,
+            receipts: false,
+            filter: Filter::default(),
+            hash_locks: HashLocks::default(),
+            dead_letters,
+        }
+    }
+
+    /// Write a receipt to each drive once it is fully flushed.
+    pub fn with_receipts(mut self) -> Self {
+        self.receipts = true;
+        self
+    }
+
+    /// Only flush the files which the `filter` accepts.
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Never import the devices of the `sources` (ex: a source on a USB drive, which is already watched).
+    pub fn with_sources(mut self, sources: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.exclude.extend(sources);
+        self
+    }
+
+    /// Share the hash locks with the syncer's flush workers (see `HashLocks`).
+    pub fn with_hash_locks(mut self, hash_locks: HashLocks) -> Self {
+        self.hash_locks = hash_locks;
+        self
+    }
+
+    /// Volumes which are already mounted when we start are imported too (they may have been plugged in while we weren't watching).
+    /// Once `stop` changes, no new volumes are imported, and this returns when the in-flight imports finish.
+    pub async fn watch(self, mut stop: watch::Receiver<()>) {
+        let mut mounted: HashSet<Volume> = HashSet::default();
+        let mut imports = JoinSet::new();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = stop.changed() => break,
+            }
+
+            // Reap the finished imports.
+            while imports.try_join_next().is_some() {}
+
+            let volumes = match mounted_volumes(&self.mount_root, &self.exclude).await {
+                Ok(volumes) => volumes,
+                Err(e) => {
+                    println!("failed to read mounts: {e:?}");
+                    continue;
+                }
+            };
+
+            // Forget the unmounted volumes, so that plugging them back in imports them again.
+            mounted.retain(|volume| volumes.contains(volume));
+
+            for volume in volumes {
+                if mounted.insert(volume.clone()) {
+                    println!("mounted {:?} at {:?}", volume.name(), volume.mount_point);
+                    imports.spawn(import(
+                        volume,
+                        self.database.clone(),
+                        self.filesystem.clone(),
+                        self.receipts,
+                        self.filter.clone(),
+                        self.hash_locks.clone(),
+                        self.dead_letters.clone(),
+                    ));
+                }
+            }
+        }
+
+        imports.join_all().await;
+    }
+}
+
+async fn import(
+    volume: Volume,
+    database: String,
+    filesystem: MediaFilesystem,
+    receipts: bool,
+    filter: Filter,
+    hash_locks: HashLocks,
+    dead_letters: DeadLetterQueue,
+) {
+    // Nothing has changed since the drive's last import.
+    if already_imported(&volume.mount_point, &filter).await {
+        println!("already imported {:?}", volume.name());
+        return;
+    }
+
+    let (Ok(mut index_db), Ok(system_db)) = (connect(&database).await, connect(&database).await)
+    else {
+        dead_letters.push_retry(
+            Retry::Flush,
+            "import",
+            vec![volume.mount_point.clone()],
+            format!("failed to connect to the database for {}", volume.name()),
+        );
+        return;
+    };
+    // The import is still flushed if it can't be recorded - the record is only informational.
+    let import_id = index_db.device_import_start(&volume).await;
+    let mut system = MediaSystem::new(system_db, filesystem).with_hash_locks(hash_locks);
+
+    match system.flush_drive(&volume.mount_point, &filter).await {
+        Ok(report) => {
+            println!(
+                "imported {:?}: {} files ({} bytes), {} failed, {} skipped",
+                volume.name(),
+                report.files,
+                report.bytes,
+                report.failed.len(),
+                report.skipped
+            );
+
+            if let Ok(id) = import_id
+                && index_db
+                    .device_import_finish(
+                        id,
+                        report.files,
+                        report.bytes,
+                        report.failed.len(),
+                        report.skipped,
+                    )
+                    .await
+                    .is_err()
+            {
+                println!("failed to record import {id} of {:?}", volume.name());
+            }
+
+            let run_id = import_id
+                .map(|id| id.to_string())
+                .unwrap_or_else(|_| run_id());
+
+            // The drive may well be read-only, and the receipt is only a convenience.
+            if receipts
+                && let Some(receipt) = report.receipt(run_id, &volume.mount_point)
+                && let Err(e) = write_receipt(&volume.mount_point, &receipt).await
+            {
+                println!("failed to write the receipt to {:?}: {e:?}", volume.name());
+            }
+
+            if !report.failed.is_empty() {
+                dead_letters.push_retry(
+                    Retry::Flush,
+                    "import",
+                    report.failed,
+                    format!("drive flush of {} failed", volume.name()),
+                );
+            }
+        }
+        Err(_) => dead_letters.push_retry(
+            Retry::Flush,
+            "import",
+            vec![volume.mount_point.clone()],
+            format!("drive flush of {} failed", volume.name()),
+        ),
+    }
+}
@@ -0,0 +1,191 @@
+use crate::listen::SourceEvent;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Detects when files have finished being written, so they aren't flushed mid-write.
+/// A file is complete once its `(size, mtime)` stops changing for the quiet period, or its writer closes it.
+/// Repeated events for the same path are coalesced, so each completed write is reported once.
+pub struct StabilityDetector {
+    quiet: Duration,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+struct Pending {
+    stat: Option<(u64, SystemTime)>,
+    changed_at: Instant,
+}
+
+impl StabilityDetector {
+    pub fn new(quiet: Duration) -> Self {
+        Self {
+            quiet,
+            pending: HashMap::default(),
+        }
+    }
+
+    /// Note write activity on the path.
+    pub fn changed(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(
+            path,
+            Pending {
+                stat: None,
+                changed_at: now,
+            },
+        );
+    }
+
+    /// Note that the writer has closed the path, which makes it complete.
+    pub fn closed(&mut self, path: PathBuf) -> PathBuf {
+        self.pending.remove(&path);
+        path
+    }
+
+    /// Take the paths which have been stable for the quiet period.
+    pub async fn poll(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut complete = Vec::default();
+        let mut gone = Vec::default();
+
+        for (path, pending) in self.pending.iter_mut() {
+            let stat = match tokio::fs::metadata(path).await {
+                // Without mtimes (on some platforms), the size alone has to do.
+                Ok(metadata) => (
+                    metadata.len(),
+                    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                ),
+                Err(_) => {
+                    // The file was removed (or renamed) before it completed - there's nothing to flush.
+                    gone.push(path.clone());
+                    continue;
+                }
+            };
+
+            if pending.stat != Some(stat) {
+                pending.stat = Some(stat);
+                pending.changed_at = now;
+            } else if now.duration_since(pending.changed_at) >= self.quiet {
+                complete.push(path.clone());
+            }
+        }
+
+        for path in gone.iter().chain(complete.iter()) {
+            self.pending.remove(path);
+        }
+
+        complete
+    }
+
+    /// Runs the detector, passing each completed path to the `callback`.
+    /// Up to `queue` events are buffered while the detector is busy checking the pending files.
+    pub fn spawn<C, F>(mut self, queue: usize, callback: C) -> mpsc::Sender<SourceEvent>
+    where
+        C: Fn(PathBuf) -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let (tx, mut rx) = mpsc::channel(queue.max(1));
+        let mut interval = tokio::time::interval((self.quiet / 4).max(Duration::from_millis(100)));
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    event = rx.recv() => match event {
+                        Some(SourceEvent::Changed(path)) => self.changed(path, Instant::now()),
+                        Some(SourceEvent::Closed(path)) => callback(self.closed(path)).await,
+                        None => break,
+                    },
+                    _ = interval.tick() => {
+                        for path in self.poll(Instant::now()).await {
+                            callback(path).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        tx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const QUIET: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn complete_after_quiet_period() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let mut detector = StabilityDetector::new(QUIET);
+        let start = Instant::now();
+
+        detector.changed(path.clone(), start);
+        assert!(detector.poll(start).await.is_empty());
+        assert!(detector.poll(start + QUIET / 2).await.is_empty());
+        assert_eq!(detector.poll(start + QUIET).await, vec![path]);
+        assert!(detector.poll(start + QUIET * 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn growing_file_is_not_complete() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let mut detector = StabilityDetector::new(QUIET);
+        let start = Instant::now();
+
+        detector.changed(path.clone(), start);
+        assert!(detector.poll(start).await.is_empty());
+        tokio::fs::write(&path, b"hello world").await.unwrap();
+        assert!(detector.poll(start + QUIET).await.is_empty());
+        assert_eq!(detector.poll(start + QUIET * 2).await, vec![path]);
+    }
+
+    #[tokio::test]
+    async fn repeated_changes_are_coalesced() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let mut detector = StabilityDetector::new(QUIET);
+        let start = Instant::now();
+
+        detector.changed(path.clone(), start);
+        detector.changed(path.clone(), start);
+        assert!(detector.poll(start).await.is_empty());
+        detector.changed(path.clone(), start + QUIET / 2);
+        assert!(detector.poll(start + QUIET / 2).await.is_empty());
+        assert!(detector.poll(start + QUIET).await.is_empty());
+        assert_eq!(detector.poll(start + QUIET / 2 + QUIET).await, vec![path]);
+    }
+
+    #[tokio::test]
+    async fn closed_is_complete() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let mut detector = StabilityDetector::new(QUIET);
+        let start = Instant::now();
+
+        detector.changed(path.clone(), start);
+        assert_eq!(detector.closed(path.clone()), path);
+        assert!(detector.poll(start + QUIET * 2).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removed_file_is_dropped() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.jpg");
+        tokio::fs::write(&path, b"hello").await.unwrap();
+        let mut detector = StabilityDetector::new(QUIET);
+        let start = Instant::now();
+
+        detector.changed(path.clone(), start);
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(detector.poll(start + QUIET).await.is_empty());
+        assert!(detector.pending.is_empty());
+    }
+}